@@ -0,0 +1,15 @@
+CREATE TABLE IF NOT EXISTS inverter_messages
+(
+    id     SERIAL PRIMARY KEY,
+    raw    BYTEA       NOT NULL,
+    type   TEXT        NOT NULL,
+    header BYTEA       NOT NULL,
+    time   TIMESTAMPTZ NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS message_data
+(
+    message_id INTEGER NOT NULL REFERENCES inverter_messages (id),
+    key        TEXT    NOT NULL,
+    value      TEXT    NOT NULL
+);
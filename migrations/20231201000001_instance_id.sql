@@ -0,0 +1,2 @@
+ALTER TABLE inverter_messages
+    ADD COLUMN IF NOT EXISTS instance_id TEXT;
@@ -0,0 +1,12 @@
+ALTER TABLE inverter_messages
+    ADD COLUMN IF NOT EXISTS inverter_sn TEXT;
+
+UPDATE inverter_messages m
+SET inverter_sn = d.value
+FROM message_data d
+WHERE d.message_id = m.id
+  AND d.key = 'Inverter SN'
+  AND m.inverter_sn IS NULL;
+
+CREATE INDEX IF NOT EXISTS inverter_messages_inverter_sn_time
+    ON inverter_messages (inverter_sn, time DESC);
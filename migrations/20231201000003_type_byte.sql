@@ -0,0 +1,7 @@
+ALTER TABLE inverter_messages
+    ADD COLUMN IF NOT EXISTS type_byte SMALLINT;
+
+UPDATE inverter_messages
+SET type_byte = get_byte(header, 7)
+WHERE type_byte IS NULL
+  AND length(header) > 7;
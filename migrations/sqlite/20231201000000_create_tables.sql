@@ -0,0 +1,15 @@
+CREATE TABLE IF NOT EXISTS inverter_messages
+(
+    id     INTEGER PRIMARY KEY AUTOINCREMENT,
+    raw    BLOB    NOT NULL,
+    type   TEXT    NOT NULL,
+    header BLOB    NOT NULL,
+    time   TEXT    NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS message_data
+(
+    message_id INTEGER NOT NULL REFERENCES inverter_messages (id),
+    key        TEXT    NOT NULL,
+    value      TEXT    NOT NULL
+);
@@ -0,0 +1,2 @@
+ALTER TABLE inverter_messages
+    ADD COLUMN instance_id TEXT;
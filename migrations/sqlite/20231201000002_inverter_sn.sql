@@ -0,0 +1,5 @@
+ALTER TABLE inverter_messages
+    ADD COLUMN inverter_sn TEXT;
+
+CREATE INDEX IF NOT EXISTS inverter_messages_inverter_sn_time
+    ON inverter_messages (inverter_sn, time DESC);
@@ -0,0 +1,2 @@
+ALTER TABLE inverter_messages
+    ADD COLUMN type_byte SMALLINT;
@@ -0,0 +1,171 @@
+use crate::ws::{self, LiveFeed};
+use axum::extract::{FromRef, Path, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::{Json, Router};
+use chrono::{DateTime, Utc};
+use growatt_server::mappings::Mappings;
+use growatt_server::Datatype;
+use serde::Serialize;
+use sqlx::PgPool;
+use std::collections::HashMap;
+use std::io;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tokio_util::sync::CancellationToken;
+
+/// Read-only HTTP API exposing the data stored in the database:
+/// - `GET /inverters`: serial numbers of the inverters that sent at least one message
+/// - `GET /inverters/{sn}/latest`: the values of the most recent message of an inverter
+/// - `GET /fragments`: the fields that can be decoded, with their type and unit
+/// - `GET /ws`: WebSocket streaming the messages as they are received, see [`ws::handler`]
+///
+/// The stored data can be queried only on PostgreSQL: without `db_pool` the `/inverters`
+/// endpoints are not served.
+pub async fn serve(
+    port: u16,
+    db_pool: Option<PgPool>,
+    mappings: Arc<Mappings>,
+    live_feed: LiveFeed,
+    shutdown: CancellationToken,
+) -> io::Result<()> {
+    let mut app = Router::new()
+        .route("/fragments", get(fragments))
+        .route("/ws", get(ws::handler))
+        .with_state(ApiState {
+            mappings: mappings.clone(),
+            live_feed,
+        });
+
+    if let Some(db_pool) = db_pool {
+        app = app.merge(
+            Router::new()
+                .route("/inverters", get(inverters))
+                .route("/inverters/{sn}/latest", get(latest))
+                .with_state(DbState { db_pool, mappings }),
+        );
+    }
+
+    let listener = TcpListener::bind(("0.0.0.0", port)).await?;
+    println!("HTTP API listening on {}", listener.local_addr()?);
+
+    axum::serve(listener, app)
+        .with_graceful_shutdown(shutdown.cancelled_owned())
+        .await
+}
+
+#[derive(Clone)]
+struct ApiState {
+    mappings: Arc<Mappings>,
+    live_feed: LiveFeed,
+}
+
+#[derive(Clone)]
+struct DbState {
+    db_pool: PgPool,
+    mappings: Arc<Mappings>,
+}
+
+impl FromRef<ApiState> for LiveFeed {
+    fn from_ref(state: &ApiState) -> Self {
+        state.live_feed.clone()
+    }
+}
+
+#[derive(Serialize)]
+struct LatestReading {
+    time: DateTime<Utc>,
+    data: HashMap<String, String>,
+    /// Units of the fields that have one in the mapping
+    units: HashMap<String, String>,
+}
+
+#[derive(Serialize)]
+struct Fragment<'a> {
+    name: &'a str,
+    #[serde(rename = "type")]
+    fragment_type: &'a Datatype,
+    unit: Option<&'a str>,
+}
+
+async fn fragments(State(state): State<ApiState>) -> Response {
+    let mut fragments: Vec<Fragment> = Vec::new();
+    // The same field may be in more than one mapping
+    for fragment in state.mappings.fragments() {
+        if !fragments.iter().any(|f| f.name == fragment.name) {
+            fragments.push(Fragment {
+                name: &fragment.name,
+                fragment_type: &fragment.fragment_type,
+                unit: fragment.unit.as_deref(),
+            });
+        }
+    }
+
+    Json(fragments).into_response()
+}
+
+async fn inverters(State(DbState { db_pool, .. }): State<DbState>) -> Response {
+    let serials = sqlx::query_scalar!(
+        r#"SELECT DISTINCT inverter_sn AS "inverter_sn!" FROM inverter_messages WHERE inverter_sn IS NOT NULL ORDER BY 1"#
+    )
+    .fetch_all(&db_pool)
+    .await;
+
+    match serials {
+        Ok(serials) => Json(serials).into_response(),
+        Err(e) => internal_error(e),
+    }
+}
+
+async fn latest(State(state): State<DbState>, Path(sn): Path<String>) -> Response {
+    let db_pool = &state.db_pool;
+
+    let message = sqlx::query!(
+        "SELECT id, time FROM inverter_messages WHERE inverter_sn = $1 ORDER BY time DESC LIMIT 1",
+        sn
+    )
+    .fetch_optional(db_pool)
+    .await;
+
+    let message = match message {
+        Ok(Some(message)) => message,
+        Ok(None) => {
+            return (StatusCode::NOT_FOUND, format!("Unknown inverter {sn}")).into_response()
+        }
+        Err(e) => return internal_error(e),
+    };
+
+    let rows = sqlx::query!(
+        "SELECT key, value FROM message_data WHERE message_id = $1",
+        message.id
+    )
+    .fetch_all(db_pool)
+    .await;
+
+    match rows {
+        Ok(rows) => {
+            let data: HashMap<String, String> =
+                rows.into_iter().map(|r| (r.key, r.value)).collect();
+            let units = state
+                .mappings
+                .fragments()
+                .filter(|f| data.contains_key(&f.name))
+                .filter_map(|f| Some((f.name.clone(), f.unit.clone()?)))
+                .collect();
+
+            Json(LatestReading {
+                time: message.time,
+                data,
+                units,
+            })
+            .into_response()
+        }
+        Err(e) => internal_error(e),
+    }
+}
+
+fn internal_error(e: sqlx::Error) -> Response {
+    eprintln!("Error querying the Database for the HTTP API: {e}");
+    (StatusCode::INTERNAL_SERVER_ERROR, "Database error").into_response()
+}
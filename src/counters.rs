@@ -0,0 +1,92 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// Keeps the last value of the monotonically increasing fields (e.g. the energy totals) of every
+/// inverter, so that the difference from the previous message can be stored too.
+/// The values are kept in memory only, and are shared between all the connections.
+/// Only the live readings (the Data4 messages) are tracked: the buffered ones (Data3) are older
+/// and out of order, so their deltas would be meaningless.
+pub struct CounterTracker {
+    fields: Vec<String>,
+    last_values: Mutex<HashMap<(String, String), f64>>,
+}
+
+impl CounterTracker {
+    pub fn new(fields: Vec<String>) -> Self {
+        Self {
+            fields,
+            last_values: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// For every counter field in `data`, adds a `{field}_delta` entry with the increase since the
+    /// previous message of the same inverter. Nothing is added for the first message.
+    /// When the counter goes backwards it is considered reset (e.g. the daily totals at midnight),
+    /// so the delta is the current value itself.
+    pub fn add_deltas(&self, serial: &str, data: &mut HashMap<String, String>) {
+        if self.fields.is_empty() {
+            return;
+        }
+
+        let mut last_values = self.last_values.lock().unwrap();
+
+        for field in &self.fields {
+            let Some(current) = data.get(field).and_then(|v| v.parse::<f64>().ok()) else {
+                continue;
+            };
+
+            let previous = last_values.insert((serial.to_string(), field.clone()), current);
+
+            if let Some(previous) = previous {
+                let delta = if current >= previous {
+                    current - previous
+                } else {
+                    current
+                };
+
+                // Trims the float noise of the subtraction (e.g. 12.3 - 12.1 = 0.20000000000000107)
+                let delta = (delta * 1e6).round() / 1e6;
+
+                data.insert(format!("{field}_delta"), delta.to_string());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reading(total: &str) -> HashMap<String, String> {
+        HashMap::from([("eactotal".to_string(), total.to_string())])
+    }
+
+    #[test]
+    fn the_increase_is_stored_as_delta() {
+        let counters = CounterTracker::new(vec!["eactotal".to_string()]);
+
+        let mut first = reading("1200.5");
+        counters.add_deltas("INV7654321", &mut first);
+        assert!(!first.contains_key("eactotal_delta"));
+
+        let mut second = reading("1200.8");
+        counters.add_deltas("INV7654321", &mut second);
+        assert_eq!(second["eactotal_delta"], "0.3");
+
+        // Another inverter has counters of its own
+        let mut other = reading("5.0");
+        counters.add_deltas("INV1111111", &mut other);
+        assert!(!other.contains_key("eactotal_delta"));
+    }
+
+    #[test]
+    fn counters_going_backwards_are_reset() {
+        let counters = CounterTracker::new(vec!["eactotal".to_string()]);
+        counters.add_deltas("INV7654321", &mut reading("12.4"));
+
+        let mut after_midnight = reading("0.2");
+        counters.add_deltas("INV7654321", &mut after_midnight);
+
+        assert_eq!(after_midnight["eactotal_delta"], "0.2");
+    }
+}
@@ -1,117 +1,538 @@
 use crate::types::MessageType;
-use crate::{utils, Datatype, GrowattV6EnergyFragment};
-use chrono::{DateTime, Local};
+use crate::{utils, Datatype, Endianness, GrowattV6EnergyFragment, Role};
+use chrono::{DateTime, Local, NaiveDateTime, TimeZone};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
 use std::collections::HashMap;
-use std::f32;
-use std::sync::Arc;
+use std::fmt;
 
-#[derive(Debug)]
+/// Key under which the datalogger serial number is stored, the same used by the mapping files.
+const LOGGER_SN: &str = "Logger SN";
+const INVERTER_SN: &str = "Inverter SN";
+const PING_SERIAL_LEN: usize = 30;
+/// Format of the values of the `date` fragments
+const DATE_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+/// Appended to the name of a fragment whose value could not be scaled, e.g. `PV1 Voltage_quality`
+pub const QUALITY_SUFFIX: &str = "_quality";
+/// Quality of the values stored as read, without the fraction, scale and bias of the fragment
+pub const RAW_QUALITY: &str = "raw";
+
+#[derive(Serialize, Deserialize)]
 pub struct DataMessage {
     pub raw: Vec<u8>,
     pub header: Vec<u8>,
     pub data_type: MessageType,
     pub data: HashMap<String, String>,
     pub time: DateTime<Local>,
+    /// Name of the field holding the serial number of the inverter
+    serial_field: Option<String>,
+}
+
+/// Decodes a single message, as received from the datalogger, picking the parser from its type.
+/// If `scrambled` is true the message is unscrambled first, with `mask` or else the default one.
+/// The message must be complete: the length declared by its header is checked against its size,
+/// e.g. to split a stream of messages use [`utils::next_frame`] first.
+pub fn decode(
+    mapping: &[GrowattV6EnergyFragment],
+    frame: &[u8],
+    scrambled: bool,
+    mask: Option<&[u8]>,
+) -> Result<DataMessage, String> {
+    if frame.len() < 8 {
+        return Err(format!(
+            "The message is {} bytes long, too short for the header",
+            frame.len()
+        ));
+    }
+
+    // The bytes up to the length field, the ones counted by it, then the CRC
+    let declared = 6 + usize::from(u16::from_be_bytes([frame[4], frame[5]])) + 2;
+    if declared != frame.len() {
+        return Err(format!(
+            "The message declares {declared} bytes, but it is {} bytes long",
+            frame.len()
+        ));
+    }
+
+    let bytes = match scrambled {
+        true => Cow::Owned(utils::unscramble_data(frame, mask)),
+        false => Cow::Borrowed(frame),
+    };
+
+    match MessageType::from(bytes[7]) {
+        MessageType::Data3 => DataMessage::data3(mapping, &bytes),
+        MessageType::Data4 => DataMessage::data4(mapping, &bytes),
+        MessageType::Ping => DataMessage::ping(&bytes),
+        data_type => DataMessage::placeholder(&bytes, data_type),
+    }
+}
+
+/// Reads only the inverter serial number of a Data3 or Data4 message, e.g. to pick the mapping used to
+/// decode the rest.
+pub fn peek_inverter_serial(mapping: &[GrowattV6EnergyFragment], bytes: &[u8]) -> Option<String> {
+    let fragment = serial_fragment(mapping)?;
+    let mut message = DataMessage::data4(std::slice::from_ref(fragment), bytes).ok()?;
+
+    message.data.remove(&fragment.name)
+}
+
+/// The fragment with the serial number of the inverter: the one with the `serial_number` role,
+/// or else the one named `Inverter SN`, as in the older mapping files.
+fn serial_fragment(mapping: &[GrowattV6EnergyFragment]) -> Option<&GrowattV6EnergyFragment> {
+    mapping
+        .iter()
+        .find(|f| f.role == Some(Role::SerialNumber))
+        .or_else(|| mapping.iter().find(|f| f.name == INVERTER_SN))
+}
+
+/// Decodes a big-endian unsigned integer of up to 8 bytes.
+fn unsigned_value(slice: &[u8]) -> Option<u64> {
+    if slice.len() > 8 {
+        return None;
+    }
+
+    let mut eight_bytes = [0u8; 8];
+    eight_bytes[8 - slice.len()..].copy_from_slice(slice);
+
+    Some(u64::from_be_bytes(eight_bytes))
+}
+
+/// Decodes a big-endian two's complement integer of up to 8 bytes, extending its sign.
+fn signed_value(slice: &[u8]) -> Option<i64> {
+    if slice.is_empty() || slice.len() > 8 {
+        return None;
+    }
+
+    let fill = if slice[0] & 0x80 != 0 { 0xFF } else { 0x00 };
+    let mut eight_bytes = [fill; 8];
+    eight_bytes[8 - slice.len()..].copy_from_slice(slice);
+
+    Some(i64::from_be_bytes(eight_bytes))
+}
+
+/// Name of a bit of a `bitflags` or `status_word` fragment, `bit_<n>` when it is not named.
+fn flag_name(fragment: &GrowattV6EnergyFragment, bit: usize) -> String {
+    fragment
+        .flags
+        .as_ref()
+        .and_then(|flags| flags.get(bit))
+        .cloned()
+        .unwrap_or_else(|| format!("bit_{bit}"))
+}
+
+/// Largest integer that f64 can represent exactly.
+const MAX_EXACT_F64: u128 = 1 << f64::MANTISSA_DIGITS;
+
+/// Divides the raw value by the fraction of the fragment.
+/// Returns `None` when that would give a wrong number, i.e. with a zero divisor (inf/NaN) or a
+/// value too large to be represented precisely: the raw value is then stored, flagged as such.
+fn apply_fraction(fragment: &GrowattV6EnergyFragment, raw: i128) -> Option<String> {
+    match fragment.fraction {
+        Some(0) => {
+            eprintln!(
+                "Fragment {} has a fraction of 0, storing the raw value {}",
+                fragment.name, raw
+            );
+            None
+        }
+        None | Some(1) => Some(raw.to_string()),
+        Some(fraction) => {
+            if raw.unsigned_abs() > MAX_EXACT_F64 {
+                eprintln!(
+                    "Fragment {} has a value too large to be scaled precisely, storing the raw value {}",
+                    fragment.name, raw
+                );
+                return None;
+            }
+
+            Some((raw as f64 / f64::from(fraction)).to_string())
+        }
+    }
+}
+
+/// Applies the linear transform of the fragment, `value * scale + bias`, when it has one.
+/// Values that are not numbers (e.g. unreadable ASCII numbers) are kept as they are.
+/// Returns `None`, like [`apply_fraction`], when the value is too large to be scaled precisely.
+fn apply_scale(fragment: &GrowattV6EnergyFragment, value: &str) -> Option<String> {
+    if fragment.scale.is_none() && fragment.bias.is_none() {
+        return Some(value.to_string());
+    }
+
+    let too_large = value
+        .parse::<i128>()
+        .is_ok_and(|raw| raw.unsigned_abs() > MAX_EXACT_F64);
+    if too_large {
+        eprintln!(
+            "Fragment {} has a value too large to be scaled precisely, storing the raw value {}",
+            fragment.name, value
+        );
+        return None;
+    }
+
+    let Ok(number) = value.parse::<f64>() else {
+        return Some(value.to_string());
+    };
+
+    let scaled = match fragment.scale {
+        // Scales like 0.1 can't be represented exactly, so multiplying by them would give e.g.
+        // 0.30000000000000004 instead of 0.3, while dividing by their inverse gives the nearest
+        // value.
+        Some(scale) if scale.abs() < 1.0 && (1.0 / scale).fract() == 0.0 => number / (1.0 / scale),
+        Some(scale) => number * scale,
+        None => number,
+    };
+
+    // The float arithmetic adds noise (e.g. 1234 * 0.1 - 100 = 23.400000000000006), so the result
+    // is rounded to the decimals that the value, the scale and the bias can give.
+    let precision = (decimals(value) + fragment.scale.map_or(0, |s| decimals(&s.to_string())))
+        .max(fragment.bias.map_or(0, |b| decimals(&b.to_string())));
+    let result = scaled + fragment.bias.unwrap_or_default();
+
+    match format!("{result:.precision$}").parse::<f64>() {
+        // Adding 0 turns -0 into 0
+        Ok(rounded) => Some((rounded + 0.0).to_string()),
+        Err(_) => Some(result.to_string()),
+    }
+}
+
+/// Number of decimals of a number as written, e.g. 2 for `12.34`.
+fn decimals(number: &str) -> usize {
+    number
+        .split_once('.')
+        .map_or(0, |(_, decimals)| decimals.len())
 }
 
 impl DataMessage {
     pub fn data4(
-        inverter_fragments: Arc<Vec<GrowattV6EnergyFragment>>,
+        inverter_fragments: &[GrowattV6EnergyFragment],
+        bytes: &[u8],
+    ) -> Result<Self, String> {
+        Self::with_fragments(MessageType::Data4, inverter_fragments, bytes)
+    }
+
+    /// Data3 messages carry the readings buffered by the datalogger while the server could not be
+    /// reached, with the same layout of the Data4 ones.
+    pub fn data3(
+        inverter_fragments: &[GrowattV6EnergyFragment],
+        bytes: &[u8],
+    ) -> Result<Self, String> {
+        Self::with_fragments(MessageType::Data3, inverter_fragments, bytes)
+    }
+
+    fn with_fragments(
+        data_type: MessageType,
+        inverter_fragments: &[GrowattV6EnergyFragment],
         bytes: &[u8],
     ) -> Result<Self, String> {
         let bytes = bytes.to_owned();
 
-        let header: Vec<u8> = bytes[0..=7].to_vec();
+        let header: Vec<u8> = bytes
+            .get(0..=7)
+            .ok_or_else(|| {
+                format!(
+                    "{data_type:?} message too short for the header: {} bytes",
+                    bytes.len()
+                )
+            })?
+            .to_vec();
 
         let bytes = &bytes[8..];
         let mut data = HashMap::new();
 
         let time = Local::now();
 
-        for fragment in inverter_fragments.iter() {
+        // The conditional fragments are decoded last, so that the fields they depend on are ready.
+        let (unconditional, conditional): (Vec<_>, Vec<_>) =
+            inverter_fragments.iter().partition(|f| f.when.is_none());
+
+        for fragment in unconditional.into_iter().chain(conditional) {
+            if let Some(condition) = &fragment.when {
+                if data.get(&condition.field) != Some(&condition.equals) {
+                    continue;
+                }
+            }
+
             let base_offset = fragment.offset as usize;
             let end_offset = base_offset + fragment.bytes_len as usize;
 
-            let slice = &bytes[base_offset..end_offset];
+            let Some(slice) = bytes.get(base_offset..end_offset) else {
+                eprintln!(
+                    "Fragment {} (bytes {base_offset}..{end_offset}) is outside of the {} bytes of the message, skipping it",
+                    fragment.name,
+                    bytes.len()
+                );
+                continue;
+            };
+
+            // Little-endian numbers are reversed, then decoded like the big-endian ones.
+            let number: Cow<[u8]> = match fragment.endian {
+                Endianness::Big => Cow::Borrowed(slice),
+                Endianness::Little => Cow::Owned(slice.iter().rev().copied().collect()),
+            };
+
+            // Set when the value could not be scaled, and is stored as read
+            let mut unscaled = false;
 
             let string_value = match &fragment.fragment_type {
-                Datatype::String => utils::hex_bytes_to_ascii(slice)
-                    .chars()
-                    .filter(|c| c.is_alphanumeric())
-                    .collect::<String>(),
-                Datatype::Date => {
-                    println!(
-                        "{}/{}/{} {}:{}:{}",
-                        slice[0], slice[1], slice[2], slice[3], slice[4], slice[5]
-                    );
-                    let year = 2000 + <i32>::from(slice[0]);
-                    let month = slice[1].into();
-                    let day = slice[2].into();
-                    let hour = slice[3].into();
-                    let min = slice[4].into();
-                    let sec = slice[5].into();
-                    let date = chrono::NaiveDate::from_ymd_opt(year, month, day)
-                        .unwrap()
-                        .and_hms_opt(hour, min, sec)
-                        .unwrap();
-
-                    date.to_string()
+                Datatype::String => {
+                    let text = if fragment.reverse_bytes {
+                        let reversed: Vec<u8> = slice.iter().rev().copied().collect();
+                        utils::hex_bytes_to_ascii(&reversed)
+                    } else {
+                        utils::hex_bytes_to_ascii(slice)
+                    };
+
+                    text.chars()
+                        .filter(|c| c.is_alphanumeric())
+                        .collect::<String>()
                 }
-                Datatype::Integer => {
-                    let mut four_bytes = Vec::from(slice);
+                Datatype::Date => {
+                    let date = match slice {
+                        [year, month, day, hour, min, sec, ..] => chrono::NaiveDate::from_ymd_opt(
+                            2000 + i32::from(*year),
+                            u32::from(*month),
+                            u32::from(*day),
+                        )
+                        .and_then(|date| {
+                            date.and_hms_opt(u32::from(*hour), u32::from(*min), u32::from(*sec))
+                        }),
+                        _ => None,
+                    };
 
-                    for _ in 0..(4 - four_bytes.len()) {
-                        four_bytes.insert(0, 0);
+                    match date {
+                        Some(date) => date.format(DATE_FORMAT).to_string(),
+                        None => {
+                            eprintln!(
+                                "Fragment {} does not contain a valid date: {:02x?}",
+                                fragment.name, slice
+                            );
+                            String::new()
+                        }
                     }
+                }
+                Datatype::Integer | Datatype::Float => {
+                    let Some(value) = unsigned_value(&number) else {
+                        eprintln!(
+                            "Fragment {} is {} bytes long, integers can be at most 8 bytes long",
+                            fragment.name,
+                            slice.len()
+                        );
+                        continue;
+                    };
+
+                    let scaled = match fragment.fragment_type {
+                        Datatype::Float => apply_fraction(fragment, i128::from(value)),
+                        _ => Some(value.to_string()),
+                    };
+                    scaled.unwrap_or_else(|| {
+                        unscaled = true;
+                        value.to_string()
+                    })
+                }
+                Datatype::Bitflags => {
+                    let Some(value) = unsigned_value(&number) else {
+                        eprintln!(
+                            "Fragment {} is {} bytes long, bitflags can be at most 8 bytes long",
+                            fragment.name,
+                            slice.len()
+                        );
+                        continue;
+                    };
 
-                    let four_bytes: [u8; 4] = four_bytes
-                        .try_into()
-                        .map_err(|e| {
-                            eprintln!("Error converting slice to array: {:?}", e);
-                            e
-                        })
-                        .unwrap();
+                    for bit in (0..slice.len() * 8).filter(|bit| value & (1 << bit) != 0) {
+                        data.insert(
+                            format!("{}.{}", fragment.name, flag_name(fragment, bit)),
+                            "true".to_string(),
+                        );
+                    }
 
-                    let value = u32::from_be_bytes(four_bytes);
+                    format!("0x{:0width$x}", value, width = slice.len() * 2)
+                }
+                Datatype::Enum => {
+                    let Some(value) = unsigned_value(&number) else {
+                        eprintln!(
+                            "Fragment {} is {} bytes long, enums can be at most 8 bytes long",
+                            fragment.name,
+                            slice.len()
+                        );
+                        continue;
+                    };
 
-                    value.to_string()
+                    u32::try_from(value)
+                        .ok()
+                        .and_then(|v| fragment.enum_map.as_ref()?.get(&v))
+                        .cloned()
+                        .unwrap_or_else(|| value.to_string())
                 }
-                Datatype::Float => {
-                    let mut four_bytes = Vec::from(slice);
+                Datatype::SignedInteger | Datatype::SignedFloat => {
+                    let Some(value) = signed_value(&number) else {
+                        eprintln!(
+                            "Fragment {} is {} bytes long, signed values can be at most 8 bytes long",
+                            fragment.name,
+                            slice.len()
+                        );
+                        continue;
+                    };
 
-                    for _ in 0..(4 - four_bytes.len()) {
-                        four_bytes.insert(0, 0);
+                    let scaled = match fragment.fragment_type {
+                        Datatype::SignedFloat => apply_fraction(fragment, i128::from(value)),
+                        _ => Some(value.to_string()),
+                    };
+                    scaled.unwrap_or_else(|| {
+                        unscaled = true;
+                        value.to_string()
+                    })
+                }
+                Datatype::Ieee754 => match <[u8; 4]>::try_from(&*number) {
+                    Ok(four_bytes) => f32::from_be_bytes(four_bytes).to_string(),
+                    Err(_) => {
+                        eprintln!(
+                            "Fragment {} is {} bytes long, IEEE-754 floats must be 4 bytes long",
+                            fragment.name,
+                            slice.len()
+                        );
+                        continue;
                     }
+                },
+                Datatype::AsciiNumber => {
+                    let text = utils::hex_bytes_to_ascii(slice);
+                    let text = text.trim_matches(|c: char| c == '\0' || c.is_whitespace());
 
-                    let four_bytes: [u8; 4] = four_bytes
-                        .try_into()
-                        .map_err(|e| {
-                            eprintln!("Error converting slice to array: {:?}", e);
-                            e
-                        })
-                        .unwrap();
+                    let scaled = match (text.parse::<i128>(), text.parse::<f64>()) {
+                        (Ok(value), _) => apply_fraction(fragment, value),
+                        // Digits with a decimal point, e.g. `12.5`
+                        (Err(_), Ok(value)) => match fragment.fraction {
+                            Some(0) => None,
+                            Some(fraction) => Some((value / f64::from(fraction)).to_string()),
+                            None => Some(value.to_string()),
+                        },
+                        (Err(_), Err(e)) => {
+                            eprintln!(
+                                "Fragment {} does not contain an ASCII number ({:?}): {}",
+                                fragment.name, text, e
+                            );
+                            Some(text.to_string())
+                        }
+                    };
+                    scaled.unwrap_or_else(|| {
+                        unscaled = true;
+                        text.to_string()
+                    })
+                }
+                Datatype::StatusWord => {
+                    let (state, sub_state) = match slice {
+                        [state, sub_state] => (*state, *sub_state),
+                        _ => {
+                            eprintln!(
+                                "Fragment {} is a status word, but it is {} bytes long instead of 2",
+                                fragment.name,
+                                slice.len()
+                            );
+                            continue;
+                        }
+                    };
 
-                    let value = u32::from_be_bytes(four_bytes);
+                    for bit in (0..8).filter(|bit| sub_state & (1 << bit) != 0) {
+                        data.insert(
+                            format!("{}.{}", fragment.name, flag_name(fragment, bit)),
+                            "true".to_string(),
+                        );
+                    }
 
-                    ((value as f32) / (fragment.fraction.unwrap_or(1) as f32)).to_string()
+                    fragment
+                        .states
+                        .as_ref()
+                        .and_then(|states| states.get(&state))
+                        .cloned()
+                        .unwrap_or_else(|| state.to_string())
                 }
             };
 
+            let string_value = match fragment.fragment_type.is_numeric() && !unscaled {
+                true => apply_scale(fragment, &string_value).unwrap_or_else(|| {
+                    unscaled = true;
+                    string_value
+                }),
+                false => string_value,
+            };
+            if unscaled {
+                data.insert(
+                    format!("{}{QUALITY_SUFFIX}", fragment.name),
+                    RAW_QUALITY.to_string(),
+                );
+            }
+
             data.insert(fragment.name.clone(), string_value);
         }
 
         Ok(Self {
             raw: bytes.into(),
             header,
-            data_type: MessageType::Data4,
+            data_type,
             data,
             time,
+            serial_field: serial_fragment(inverter_fragments).map(|f| f.name.clone()),
         })
     }
 
+    /// Ping messages are the keep-alives sent periodically by the datalogger.
+    /// Right after the 8 bytes header, the payload contains the datalogger serial number:
+    /// 10 ASCII characters, padded with zeroes up to 30 bytes in protocol v6.
+    /// The frame is then closed by the 2 bytes CRC.
+    pub fn ping(bytes: &[u8]) -> Result<Self, String> {
+        let mut message = Self::placeholder(bytes, MessageType::Ping)?;
+
+        let end = (8 + PING_SERIAL_LEN).min(bytes.len());
+        let serial = utils::hex_bytes_to_ascii(&bytes[8..end])
+            .chars()
+            .filter(|c| c.is_alphanumeric())
+            .collect::<String>();
+
+        if !serial.is_empty() {
+            message.data.insert(LOGGER_SN.to_string(), serial);
+        }
+
+        Ok(message)
+    }
+
+    pub fn logger_serial(&self) -> Option<&str> {
+        self.data.get(LOGGER_SN).map(String::as_str)
+    }
+
+    /// The type byte of the header, which tells apart the messages of `Unknown` type.
+    pub fn type_byte(&self) -> Option<i16> {
+        self.header.get(7).copied().map(i16::from)
+    }
+
+    pub fn inverter_serial(&self) -> Option<&str> {
+        self.data
+            .get(self.serial_field.as_ref()?)
+            .map(String::as_str)
+    }
+
+    /// Replaces the time the message was received with the one in the `field` date fragment,
+    /// i.e. the clock of the inverter, when present and valid.
+    pub fn use_time_from(&mut self, field: &str) {
+        let Some(value) = self.data.get(field).filter(|v| !v.is_empty()) else {
+            return;
+        };
+
+        let time = NaiveDateTime::parse_from_str(value, DATE_FORMAT)
+            .ok()
+            .and_then(|time| Local.from_local_datetime(&time).earliest());
+
+        match time {
+            Some(time) => self.time = time,
+            None => eprintln!("The value of {field} ({value}) is not a date, keeping the time the message was received"),
+        }
+    }
+
     pub fn placeholder(bytes: &[u8], message_type: MessageType) -> Result<Self, String> {
         let bytes = bytes.to_owned();
-        let header: Vec<u8> = bytes[0..=7].to_vec();
+        let header: Vec<u8> = bytes
+            .get(0..=7)
+            .ok_or_else(|| format!("Message too short for the header: {} bytes", bytes.len()))?
+            .to_vec();
 
         let time = Local::now();
 
@@ -121,6 +542,445 @@ impl DataMessage {
             data_type: message_type,
             data: Default::default(),
             time,
+            serial_field: None,
         })
     }
 }
+
+impl fmt::Debug for DataMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DataMessage")
+            .field("header", &HexDump(&self.header))
+            .field("data_type", &self.data_type)
+            .field("data", &self.data)
+            .field("time", &self.time)
+            .field("raw", &HexDump(&self.raw))
+            .finish()
+    }
+}
+
+/// Shows the bytes as an offset-annotated hex dump, which is far more readable than a list.
+struct HexDump<'a>(&'a [u8]);
+
+impl fmt::Debug for HexDump<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f)?;
+        utils::write_hex_dump(f, self.0, 16)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parses a single fragment, written as in the mapping files.
+    fn fragment(json: &str) -> GrowattV6EnergyFragment {
+        serde_json::from_str(json).unwrap()
+    }
+
+    /// An unscrambled message of the given type, with `value` at `offset` of the payload.
+    fn frame(message_type: u8, offset: usize, value: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0x00, 0x01, 0x00, 0x06, 0x00, 0x00, 0x01, message_type];
+        frame.resize(8 + offset, 0);
+        frame.extend_from_slice(value);
+        let length = (frame.len() - 6) as u16;
+        frame[4..6].copy_from_slice(&length.to_be_bytes());
+        frame.extend_from_slice(&utils::crc16(&frame).to_be_bytes());
+        frame
+    }
+
+    const PV1_VOLTAGE: &str =
+        r#"{"name": "PV1 Voltage", "offset": 10, "length": 2, "type": "float", "fraction": 10}"#;
+
+    #[test]
+    fn scrambled_messages_are_decoded_with_the_given_mask() {
+        let mapping = [fragment(PV1_VOLTAGE)];
+        let plain = frame(0x04, 10, &2305u16.to_be_bytes());
+        let mask = b"Custom".as_slice();
+        // The scrambling is a XOR, unscrambling a message scrambles it
+        let scrambled = utils::unscramble_data(&plain, Some(mask));
+
+        let message = decode(&mapping, &scrambled, true, Some(mask)).unwrap();
+        assert_eq!(message.data["PV1 Voltage"], "230.5");
+
+        // The default mask gives different bytes
+        let message = decode(&mapping, &scrambled, true, None).unwrap();
+        assert_ne!(message.data["PV1 Voltage"], "230.5");
+    }
+
+    #[test]
+    fn the_serial_number_is_found_by_its_role() {
+        let mapping = [fragment(
+            r#"{"name": "Serial", "offset": 20, "length": 10, "type": "string", "role": "serial_number"}"#,
+        )];
+        let message = frame(0x04, 20, b"INV7654321");
+
+        let decoded = DataMessage::data4(&mapping, &message).unwrap();
+        assert_eq!(decoded.inverter_serial(), Some("INV7654321"));
+        assert_eq!(
+            peek_inverter_serial(&mapping, &message).as_deref(),
+            Some("INV7654321")
+        );
+    }
+
+    #[test]
+    fn the_serial_number_falls_back_to_its_old_name() {
+        let mapping = [fragment(
+            r#"{"name": "Inverter SN", "offset": 20, "length": 10, "type": "string"}"#,
+        )];
+
+        let decoded = DataMessage::data4(&mapping, &frame(0x04, 20, b"INV7654321")).unwrap();
+        assert_eq!(decoded.inverter_serial(), Some("INV7654321"));
+    }
+
+    #[test]
+    fn unknown_types_keep_their_type_byte() {
+        let mapping = [fragment(PV1_VOLTAGE)];
+        let scrambled = utils::scramble_data(&frame(0x1b, 10, &2305u16.to_be_bytes()), None);
+
+        let message = decode(&mapping, &scrambled, true, None).unwrap();
+        assert_eq!(message.data_type, MessageType::Unknown);
+        assert_eq!(message.type_byte(), Some(0x1b));
+    }
+
+    #[test]
+    fn data3_messages_are_decoded() {
+        let mapping = [fragment(PV1_VOLTAGE)];
+        let plain = frame(0x03, 10, &2305u16.to_be_bytes());
+        let scrambled = utils::scramble_data(&plain, None);
+
+        let message = decode(&mapping, &scrambled, true, None).unwrap();
+        assert_eq!(message.data_type, MessageType::Data3);
+        assert_eq!(message.data["PV1 Voltage"], "230.5");
+    }
+
+    #[test]
+    fn incomplete_messages_are_rejected() {
+        let mapping = [fragment(PV1_VOLTAGE)];
+        let plain = frame(0x04, 10, &2305u16.to_be_bytes());
+
+        let error = decode(&mapping, &plain[..plain.len() - 1], false, None).unwrap_err();
+        assert!(error.contains("declares"), "{error}");
+
+        let mut two = plain.clone();
+        two.extend_from_slice(&plain);
+        assert!(decode(&mapping, &two, false, None).is_err());
+
+        assert!(decode(&mapping, &plain[..5], false, None).is_err());
+    }
+
+    #[test]
+    fn enums_are_replaced_by_their_label() {
+        let mapping = [fragment(
+            r#"{"name": "Mode", "offset": 0, "length": 2, "type": "enum", "enum_map": {"1": "Normal", "3": "Fault"}}"#,
+        )];
+
+        let message = DataMessage::data4(&mapping, &frame(0x04, 0, &[0x00, 0x03])).unwrap();
+        assert_eq!(message.data["Mode"], "Fault");
+
+        // Without a label the number is kept
+        let message = DataMessage::data4(&mapping, &frame(0x04, 0, &[0x00, 0x07])).unwrap();
+        assert_eq!(message.data["Mode"], "7");
+    }
+
+    #[test]
+    fn bitflags_are_stored_one_by_one() {
+        let mapping = [fragment(
+            r#"{"name": "fault", "offset": 0, "length": 2, "type": "bitflags", "flags": ["grid_lost", "over_temperature"]}"#,
+        )];
+
+        // Bits 0 and 9
+        let message = DataMessage::data4(&mapping, &frame(0x04, 0, &[0x02, 0x01])).unwrap();
+
+        assert_eq!(message.data["fault"], "0x0201");
+        assert_eq!(message.data["fault.grid_lost"], "true");
+        assert_eq!(message.data["fault.bit_9"], "true");
+        assert!(!message.data.contains_key("fault.over_temperature"));
+    }
+
+    #[test]
+    fn ascii_numbers_are_decoded() {
+        let mapping = [fragment(
+            r#"{"name": "Energy", "offset": 0, "length": 4, "type": "ascii_number"}"#,
+        )];
+
+        let message = DataMessage::data4(&mapping, &frame(0x04, 0, b"1234")).unwrap();
+        assert_eq!(message.data["Energy"], "1234");
+    }
+
+    #[test]
+    fn ascii_numbers_with_a_zero_fraction_are_flagged() {
+        let mapping = [fragment(
+            r#"{"name": "Energy", "offset": 0, "length": 4, "type": "ascii_number", "fraction": 0}"#,
+        )];
+
+        let message = DataMessage::data4(&mapping, &frame(0x04, 0, b"1234")).unwrap();
+        assert_eq!(message.data["Energy"], "1234");
+        assert_eq!(message.data["Energy_quality"], RAW_QUALITY);
+    }
+
+    #[test]
+    fn signed_values_keep_their_sign() {
+        let mapping = [
+            fragment(r#"{"name": "Power", "offset": 0, "length": 2, "type": "sint"}"#),
+            fragment(
+                r#"{"name": "Power factor", "offset": 2, "length": 2, "type": "sfloat", "fraction": 1000}"#,
+            ),
+        ];
+
+        let message =
+            DataMessage::data4(&mapping, &frame(0x04, 0, &[0xff, 0x9c, 0xfc, 0x18])).unwrap();
+        assert_eq!(message.data["Power"], "-100");
+        assert_eq!(message.data["Power factor"], "-1");
+    }
+
+    #[test]
+    fn ieee754_floats_are_decoded() {
+        let mapping = [fragment(
+            r#"{"name": "Frequency", "offset": 0, "length": 4, "type": "float32"}"#,
+        )];
+
+        let message =
+            DataMessage::data4(&mapping, &frame(0x04, 0, &50.25f32.to_be_bytes())).unwrap();
+        assert_eq!(message.data["Frequency"], "50.25");
+    }
+
+    #[test]
+    fn eight_byte_integers_are_decoded() {
+        let mapping = [fragment(
+            r#"{"name": "Energy", "offset": 0, "length": 8, "type": "int"}"#,
+        )];
+        let raw = u64::MAX - 1;
+
+        let message = DataMessage::data4(&mapping, &frame(0x04, 0, &raw.to_be_bytes())).unwrap();
+        assert_eq!(message.data["Energy"], "18446744073709551614");
+    }
+
+    #[test]
+    fn reversed_strings_are_decoded() {
+        let mapping = [fragment(
+            r#"{"name": "Inverter SN", "offset": 0, "length": 10, "type": "string", "reverse_bytes": true}"#,
+        )];
+
+        let message = DataMessage::data4(&mapping, &frame(0x04, 0, b"1234567VNI")).unwrap();
+        assert_eq!(message.data["Inverter SN"], "INV7654321");
+    }
+
+    #[test]
+    fn status_words_are_decoded() {
+        let mapping = [fragment(
+            r#"{"name": "Status", "offset": 0, "length": 2, "type": "status_word", "states": {"1": "Normal", "3": "Fault"}, "flags": ["derating"]}"#,
+        )];
+
+        let message = DataMessage::data4(&mapping, &frame(0x04, 0, &[0x01, 0x01])).unwrap();
+        assert_eq!(message.data["Status"], "Normal");
+        assert_eq!(message.data["Status.derating"], "true");
+
+        // Without a name the state is kept as a number
+        let message = DataMessage::data4(&mapping, &frame(0x04, 0, &[0x05, 0x00])).unwrap();
+        assert_eq!(message.data["Status"], "5");
+        assert!(!message.data.contains_key("Status.derating"));
+    }
+
+    #[test]
+    fn conditional_fragments_depend_on_their_field() {
+        let mapping = [
+            fragment(r#"{"name": "Model", "offset": 0, "length": 1, "type": "int"}"#),
+            fragment(
+                r#"{"name": "PowerA", "offset": 1, "length": 2, "type": "int", "when": {"field": "Model", "equals": "1"}}"#,
+            ),
+            fragment(
+                r#"{"name": "PowerB", "offset": 1, "length": 2, "type": "int", "when": {"field": "Model", "equals": "2"}}"#,
+            ),
+        ];
+
+        let message = DataMessage::data4(&mapping, &frame(0x04, 0, &[2, 0x01, 0x00])).unwrap();
+        assert_eq!(message.data["PowerB"], "256");
+        assert!(!message.data.contains_key("PowerA"));
+    }
+
+    #[test]
+    fn the_bytes_are_shown_as_a_hex_dump() {
+        let bytes: Vec<u8> = (0x41..0x53).collect();
+        let message = DataMessage::placeholder(&bytes, MessageType::Unknown).unwrap();
+
+        let debug = format!("{message:?}");
+        assert!(debug.contains(
+            "raw: \n0000: 41 42 43 44 45 46 47 48  49 4a 4b 4c 4d 4e 4f 50   ABCDEFGHIJKLMNOP\n\
+            0010: 51 52                                              QR"
+        ));
+        assert!(debug.contains("header: \n0000: 41 42 43 44 45 46 47 48"));
+    }
+
+    #[test]
+    fn the_serial_of_the_pings_is_read_from_the_first_30_bytes() {
+        let mut payload = b"ABC1234567".to_vec();
+        payload.resize(PING_SERIAL_LEN, 0);
+        // Not part of the serial
+        payload.extend_from_slice(b"XY");
+
+        let message = DataMessage::ping(&frame(0x16, 0, &payload)).unwrap();
+        assert_eq!(message.logger_serial(), Some("ABC1234567"));
+
+        // A ping cut short still has its serial
+        let message = DataMessage::ping(&frame(0x16, 0, b"ABC12")[..13]).unwrap();
+        assert_eq!(message.logger_serial(), Some("ABC12"));
+
+        let message = DataMessage::ping(&frame(0x16, 0, &[0; 30])).unwrap();
+        assert_eq!(message.logger_serial(), None);
+    }
+
+    #[test]
+    fn the_date_of_the_inverter_is_the_time_of_the_message() {
+        let mapping = [fragment(
+            r#"{"name": "Date", "offset": 0, "length": 6, "type": "date"}"#,
+        )];
+
+        let mut message =
+            DataMessage::data4(&mapping, &frame(0x04, 0, &[23, 12, 1, 10, 30, 5])).unwrap();
+        message.use_time_from("Date");
+
+        let expected = Local.with_ymd_and_hms(2023, 12, 1, 10, 30, 5).unwrap();
+        assert_eq!(message.time, expected);
+    }
+
+    #[test]
+    fn invalid_dates_keep_the_time_of_reception() {
+        let mapping = [fragment(
+            r#"{"name": "Date", "offset": 0, "length": 6, "type": "date"}"#,
+        )];
+
+        // The 13th month
+        let mut message =
+            DataMessage::data4(&mapping, &frame(0x04, 0, &[23, 13, 1, 10, 30, 5])).unwrap();
+        let received = message.time;
+        message.use_time_from("Date");
+        assert_eq!(message.time, received);
+
+        // Without the field
+        message.use_time_from("Missing");
+        assert_eq!(message.time, received);
+    }
+
+    #[test]
+    fn too_large_values_are_stored_raw_and_flagged() {
+        let mapping = [fragment(
+            r#"{"name": "Energy", "offset": 0, "length": 8, "type": "sfloat", "fraction": 10, "scale": 2}"#,
+        )];
+        let raw = i64::MAX - 1;
+
+        let message = DataMessage::data4(&mapping, &frame(0x04, 0, &raw.to_be_bytes())).unwrap();
+
+        assert_eq!(message.data["Energy"], raw.to_string());
+        assert_eq!(message.data["Energy_quality"], RAW_QUALITY);
+    }
+
+    #[test]
+    fn zero_fractions_are_stored_raw_and_flagged() {
+        let mapping = [fragment(
+            r#"{"name": "Power", "offset": 0, "length": 2, "type": "float", "fraction": 0}"#,
+        )];
+
+        let message =
+            DataMessage::data4(&mapping, &frame(0x04, 0, &1234u16.to_be_bytes())).unwrap();
+
+        assert_eq!(message.data["Power"], "1234");
+        assert_eq!(message.data["Power_quality"], RAW_QUALITY);
+    }
+
+    #[test]
+    fn scaled_values_have_no_quality_flag() {
+        let mapping = [fragment(
+            r#"{"name": "Power", "offset": 0, "length": 2, "type": "float", "fraction": 10}"#,
+        )];
+
+        let message =
+            DataMessage::data4(&mapping, &frame(0x04, 0, &1234u16.to_be_bytes())).unwrap();
+
+        assert_eq!(message.data["Power"], "123.4");
+        assert!(!message.data.contains_key("Power_quality"));
+    }
+
+    #[test]
+    fn little_endian_values_are_decoded() {
+        let mapping = [fragment(
+            r#"{"name": "Power", "offset": 0, "length": 2, "type": "int", "endian": "little"}"#,
+        )];
+
+        let message = DataMessage::data4(&mapping, &frame(0x04, 0, &[0x34, 0x12])).unwrap();
+        assert_eq!(message.data["Power"], "4660");
+    }
+
+    #[test]
+    fn values_are_multiplied_by_the_scale() {
+        let mapping = [fragment(
+            r#"{"name": "Power", "offset": 0, "length": 2, "type": "int", "scale": 0.1}"#,
+        )];
+
+        let message =
+            DataMessage::data4(&mapping, &frame(0x04, 0, &1234u16.to_be_bytes())).unwrap();
+        assert_eq!(message.data["Power"], "123.4");
+
+        let message = DataMessage::data4(&mapping, &frame(0x04, 0, &3u16.to_be_bytes())).unwrap();
+        assert_eq!(message.data["Power"], "0.3");
+    }
+
+    #[test]
+    fn too_large_values_are_not_scaled() {
+        let mapping = [fragment(
+            r#"{"name": "Energy", "offset": 0, "length": 8, "type": "int", "scale": 0.1}"#,
+        )];
+        let raw = (1u64 << 53) + 1;
+
+        let message = DataMessage::data4(&mapping, &frame(0x04, 0, &raw.to_be_bytes())).unwrap();
+
+        assert_eq!(message.data["Energy"], "9007199254740993");
+        assert_eq!(message.data["Energy_quality"], RAW_QUALITY);
+    }
+
+    #[test]
+    fn too_large_values_are_not_biased() {
+        let mapping = [fragment(
+            r#"{"name": "Energy", "offset": 0, "length": 8, "type": "sint", "bias": -100}"#,
+        )];
+        let raw = -(1i64 << 53) - 1;
+
+        let message = DataMessage::data4(&mapping, &frame(0x04, 0, &raw.to_be_bytes())).unwrap();
+
+        assert_eq!(message.data["Energy"], "-9007199254740993");
+        assert_eq!(message.data["Energy_quality"], RAW_QUALITY);
+    }
+
+    #[test]
+    fn the_bias_is_added_after_the_scale() {
+        let mapping = [fragment(
+            r#"{"name": "Temperature", "offset": 0, "length": 2, "type": "int", "scale": 0.1, "bias": -100}"#,
+        )];
+
+        for (raw, expected) in [
+            (1234u16, "23.4"),
+            (1000, "0"),
+            (977, "-2.3"),
+            (1501, "50.1"),
+        ] {
+            let message =
+                DataMessage::data4(&mapping, &frame(0x04, 0, &raw.to_be_bytes())).unwrap();
+            assert_eq!(message.data["Temperature"], expected, "raw value {raw}");
+        }
+    }
+
+    #[test]
+    fn the_precision_of_the_fraction_is_kept() {
+        let mapping = [fragment(
+            r#"{"name": "Voltage", "offset": 0, "length": 2, "type": "float", "fraction": 10, "scale": 0.1, "bias": 0.25}"#,
+        )];
+
+        let message =
+            DataMessage::data4(&mapping, &frame(0x04, 0, &2305u16.to_be_bytes())).unwrap();
+        assert_eq!(message.data["Voltage"], "23.3");
+
+        let message =
+            DataMessage::data4(&mapping, &frame(0x04, 0, &2307u16.to_be_bytes())).unwrap();
+        assert_eq!(message.data["Voltage"], "23.32");
+    }
+}
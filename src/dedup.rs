@@ -0,0 +1,53 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Messages remembered at most, so that a flood of different messages can't exhaust the memory.
+const MAX_ENTRIES: usize = 4096;
+
+/// Recognizes the messages that a datalogger sends again, identical, within a short time,
+/// which would otherwise be stored twice.
+pub struct Deduplicator {
+    window: Duration,
+    seen: Mutex<HashMap<u64, Instant>>,
+}
+
+impl Deduplicator {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            seen: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns true when the same serial sent the same payload within the window.
+    pub fn is_duplicate(&self, serial: &str, payload: &[u8]) -> bool {
+        let mut hasher = DefaultHasher::new();
+        (serial, payload).hash(&mut hasher);
+        let key = hasher.finish();
+
+        let now = Instant::now();
+        let mut seen = self.seen.lock().unwrap();
+
+        if seen
+            .get(&key)
+            .is_some_and(|&time| now.duration_since(time) < self.window)
+        {
+            return true;
+        }
+
+        if seen.len() >= MAX_ENTRIES {
+            seen.retain(|_, time| now.duration_since(*time) < self.window);
+        }
+        if seen.len() >= MAX_ENTRIES {
+            if let Some(oldest) = seen.iter().min_by_key(|(_, time)| **time).map(|(k, _)| *k) {
+                seen.remove(&oldest);
+            }
+        }
+        seen.insert(key, now);
+
+        false
+    }
+}
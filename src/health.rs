@@ -0,0 +1,144 @@
+use crate::storage::Storage;
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::routing::get;
+use axum::{Json, Router};
+use chrono::{DateTime, Local};
+use growatt_server::types::MessageType;
+use growatt_server::DataMessage;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::TcpListener;
+use tokio_util::sync::CancellationToken;
+
+/// A stalled database must not hang the probes.
+const DATABASE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// State of the server reported by the `/health` endpoint, shared with the connections.
+pub struct Health {
+    started: Instant,
+    accepting: AtomicBool,
+    last_message: Mutex<Option<DateTime<Local>>>,
+    inverters: Mutex<HashMap<String, InverterStatus>>,
+}
+
+/// When an inverter was last heard from, e.g. to notice the ones gone offline.
+#[derive(Serialize, Clone)]
+struct InverterStatus {
+    last_seen: DateTime<Local>,
+    messages: u64,
+    by_type: HashMap<MessageType, u64>,
+}
+
+impl Default for Health {
+    fn default() -> Self {
+        Self {
+            started: Instant::now(),
+            accepting: AtomicBool::new(false),
+            last_message: Mutex::new(None),
+            inverters: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl Health {
+    pub fn set_accepting(&self, accepting: bool) {
+        self.accepting.store(accepting, Ordering::Relaxed);
+    }
+
+    /// The messages are counted by the serial number of their inverter, or of the datalogger
+    /// for those that don't carry it (e.g. the pings).
+    pub fn message_received(&self, message: &DataMessage) {
+        let now = Local::now();
+        *self.last_message.lock().unwrap() = Some(now);
+
+        let Some(serial) = message.inverter_serial().or(message.logger_serial()) else {
+            return;
+        };
+
+        let mut inverters = self.inverters.lock().unwrap();
+        let status = inverters
+            .entry(serial.to_string())
+            .or_insert_with(|| InverterStatus {
+                last_seen: now,
+                messages: 0,
+                by_type: HashMap::new(),
+            });
+        status.last_seen = now;
+        status.messages += 1;
+        *status.by_type.entry(message.data_type).or_default() += 1;
+    }
+}
+
+#[derive(Serialize)]
+struct Report {
+    accepting: bool,
+    /// `ok`, `disabled` when no database is configured, or the error
+    database: String,
+    uptime_secs: u64,
+    last_message: Option<DateTime<Local>>,
+}
+
+#[derive(Clone)]
+struct HealthState {
+    health: Arc<Health>,
+    storage: Option<Arc<dyn Storage>>,
+}
+
+/// Serves `GET /health`, meant for liveness and readiness probes: it answers 200 when the server
+/// is accepting connections and the database is reachable, 503 otherwise.
+/// `GET /health/inverters` reports the [`InverterStatus`] of every serial number seen since the
+/// start.
+pub async fn serve(
+    port: u16,
+    health: Arc<Health>,
+    storage: Option<Arc<dyn Storage>>,
+    shutdown: CancellationToken,
+) -> io::Result<()> {
+    let app = Router::new()
+        .route("/health", get(report))
+        .route("/health/inverters", get(inverters))
+        .with_state(HealthState { health, storage });
+
+    let listener = TcpListener::bind(("0.0.0.0", port)).await?;
+    println!("Health endpoint listening on {}", listener.local_addr()?);
+
+    axum::serve(listener, app)
+        .with_graceful_shutdown(shutdown.cancelled_owned())
+        .await
+}
+
+async fn report(State(state): State<HealthState>) -> (StatusCode, Json<Report>) {
+    let database = match &state.storage {
+        None => Ok("disabled".to_string()),
+        Some(storage) => match tokio::time::timeout(DATABASE_TIMEOUT, storage.ping()).await {
+            Ok(Ok(())) => Ok("ok".to_string()),
+            Ok(Err(e)) => Err(e.to_string()),
+            Err(_) => Err(format!("no answer within {DATABASE_TIMEOUT:?}")),
+        },
+    };
+
+    let accepting = state.health.accepting.load(Ordering::Relaxed);
+    let status = if accepting && database.is_ok() {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    let report = Report {
+        accepting,
+        database: database.unwrap_or_else(|e| e),
+        uptime_secs: state.health.started.elapsed().as_secs(),
+        last_message: *state.health.last_message.lock().unwrap(),
+    };
+
+    (status, Json(report))
+}
+
+async fn inverters(State(state): State<HealthState>) -> Json<HashMap<String, InverterStatus>> {
+    Json(state.health.inverters.lock().unwrap().clone())
+}
@@ -0,0 +1,131 @@
+//! Decoding of the messages sent by the Growatt dataloggers (protocol v6), as used by the proxy.
+//!
+//! ```
+//! use growatt_server::{decode, GrowattV6EnergyFragment, MessageType};
+//!
+//! let mapping: Vec<GrowattV6EnergyFragment> = serde_json::from_str(
+//!     r#"[{"name": "PV1 Voltage", "offset": 77, "length": 2, "type": "float", "fraction": 10}]"#,
+//! )?;
+//!
+//! // An unscrambled Data4 message: the header, 100 bytes of readings and the CRC
+//! let mut frame = vec![0x00, 0x01, 0x00, 0x06, 0x00, 102, 0x01, 0x04];
+//! frame.resize(8 + 100, 0);
+//! frame[8 + 77..8 + 79].copy_from_slice(&2305u16.to_be_bytes());
+//! frame.extend_from_slice(&growatt_server::utils::crc16(&frame).to_be_bytes());
+//!
+//! let message = decode(&mapping, &frame, false, None)?;
+//! assert_eq!(message.data_type, MessageType::Data4);
+//! assert_eq!(message.data["PV1 Voltage"], "230.5");
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+pub mod data_message;
+pub mod mappings;
+pub mod types;
+pub mod utils;
+
+pub use data_message::{decode, DataMessage};
+pub use types::MessageType;
+pub use utils::{scramble_data, unscramble_data};
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Datatype {
+    String,
+    Date,
+    #[serde(alias = "int")]
+    Integer,
+    Float,
+    /// Two's complement integer, e.g. a negative power factor
+    #[serde(alias = "sint")]
+    SignedInteger,
+    /// Two's complement integer divided by `fraction`
+    #[serde(alias = "sfloat")]
+    SignedFloat,
+    /// 32 bits IEEE-754 float, `fraction` is ignored
+    #[serde(alias = "float32")]
+    Ieee754,
+    /// A number written as ASCII digits (e.g. `31 32 33 34` for 1234) instead of binary
+    AsciiNumber,
+    /// Independent boolean flags (e.g. alarms), named through `flags` starting from the LSB
+    Bitflags,
+    /// Unsigned integer replaced by its label in `enum_map`, when present
+    Enum,
+    /// 2 bytes: the high byte is the operating state, named through `states`,
+    /// while the low byte holds the sub-state flags, named through `flags` starting from the LSB
+    StatusWord,
+}
+
+impl Datatype {
+    /// Whether the values are numbers, as opposed to text or labels.
+    pub fn is_numeric(&self) -> bool {
+        matches!(
+            self,
+            Datatype::Integer
+                | Datatype::Float
+                | Datatype::SignedInteger
+                | Datatype::SignedFloat
+                | Datatype::Ieee754
+                | Datatype::AsciiNumber
+        )
+    }
+}
+
+/// Byte order of the binary numbers
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Endianness {
+    #[default]
+    Big,
+    Little,
+}
+
+/// Special meanings of a fragment, besides its value
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    /// The serial number of the inverter; when no fragment has this role, the one named
+    /// `Inverter SN` is used
+    SerialNumber,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GrowattV6EnergyFragment {
+    pub name: String,
+    pub offset: u32,
+    #[serde(alias = "length")]
+    pub bytes_len: u32,
+    #[serde(alias = "type")]
+    pub fragment_type: Datatype,
+    pub fraction: Option<u32>,
+    /// Multiplies the numeric values, after the division by `fraction`
+    pub scale: Option<f64>,
+    /// Added to the numeric values after the scaling, for the registers stored with an offset
+    pub bias: Option<f64>,
+    /// Unit of measure of the value (e.g. `V`, `Hz`), for the consumers of the data
+    pub unit: Option<String>,
+    /// Some firmwares store strings (e.g. the serial numbers) with the bytes reversed.
+    #[serde(default)]
+    pub reverse_bytes: bool,
+    /// Some registers hold little-endian numbers, while most are big-endian.
+    #[serde(default)]
+    pub endian: Endianness,
+    /// Labels of the values of an `enum`
+    pub enum_map: Option<HashMap<u32, String>>,
+    /// Labels of the values of a `status_word` operating state
+    pub states: Option<HashMap<u8, String>>,
+    /// Names of the bits of a `bitflags` or `status_word`, from the least significant
+    pub flags: Option<Vec<String>>,
+    /// Decode the fragment only when another field has the given value
+    pub when: Option<Condition>,
+    pub role: Option<Role>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Condition {
+    pub field: String,
+    pub equals: String,
+}
@@ -0,0 +1,68 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// Reduces the volume of the logs by letting through only one message every `rate`,
+/// counted separately for each datalogger.
+pub struct LogSampler {
+    rate: u32,
+    counters: Mutex<HashMap<String, u32>>,
+}
+
+impl LogSampler {
+    pub fn new(rate: u32) -> Self {
+        Self {
+            rate: rate.max(1),
+            counters: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns true for the first message of each serial, and then once every `rate` messages.
+    pub fn should_log(&self, serial: &str) -> bool {
+        if self.rate == 1 {
+            return true;
+        }
+
+        let mut counters = self.counters.lock().unwrap();
+        let counter = counters.entry(serial.to_string()).or_insert(0);
+
+        let log = *counter == 0;
+        *counter = (*counter + 1) % self.rate;
+
+        log
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn one_message_in_ten_is_logged() {
+        let sampler = LogSampler::new(10);
+
+        let logged: Vec<usize> = (0..20)
+            .filter(|_| sampler.should_log("ABC1234567"))
+            .collect();
+        assert_eq!(logged, [0, 10]);
+    }
+
+    #[test]
+    fn each_datalogger_is_sampled_separately() {
+        let sampler = LogSampler::new(10);
+        for _ in 0..3 {
+            sampler.should_log("ABC1234567");
+        }
+
+        // The first message of another datalogger is logged anyway
+        assert!(sampler.should_log("XYZ7654321"));
+        assert!(!sampler.should_log("XYZ7654321"));
+        assert!(!sampler.should_log("ABC1234567"));
+    }
+
+    #[test]
+    fn a_rate_of_zero_logs_everything() {
+        let sampler = LogSampler::new(0);
+
+        assert!((0..5).all(|_| sampler.should_log("ABC1234567")));
+    }
+}
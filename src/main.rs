@@ -1,98 +1,251 @@
-use data_message::DataMessage;
+use clap::{Parser, Subcommand};
+use config::{Config, DbConfig, DbKind};
+use counters::CounterTracker;
+use dedup::Deduplicator;
 use futures::FutureExt;
-use serde::{Deserialize, Serialize};
-use sqlx::postgres::PgConnectOptions;
-use sqlx::PgPool;
+use growatt_server::data_message::{self, DataMessage};
+use growatt_server::mappings::{self, Mappings};
+use growatt_server::types::MessageType;
+use growatt_server::{utils, GrowattV6EnergyFragment};
+use health::Health;
+use log_sampler::LogSampler;
+use spool::Spool;
+use sqlx::migrate::Migrator;
+use sqlx::postgres::{PgConnectOptions, PgPoolOptions};
+use sqlx::{Connection, PgConnection, PgPool};
+use std::borrow::Cow;
 use std::error::Error;
 use std::fmt::Write;
-use std::io;
 use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
+use storage::{PgStorage, SqliteStorage, Storage};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::signal::unix::SignalKind;
-use tokio::task::JoinHandle;
+use tokio::sync::{mpsc, Semaphore};
+use tokio::task::JoinSet;
 use tokio::{fs, signal};
+use tokio_rustls::rustls::pki_types::ServerName;
+use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+use tokio_rustls::TlsConnector;
 use tokio_util::sync::CancellationToken;
-use types::MessageType;
+use wide_table::WideTable;
+use ws::LiveFeed;
 
-mod data_message;
-mod types;
-mod utils;
+mod api;
+mod config;
+mod counters;
+mod dedup;
+mod health;
+mod log_sampler;
+#[cfg(feature = "parquet")]
+mod parquet_sink;
+mod replay;
+mod spool;
+mod storage;
+mod wide_table;
+mod ws;
 
 const BUF_SIZE: usize = 65535;
+/// Wait after failing to accept a connection, e.g. when out of file descriptors
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_millis(100);
 
-#[derive(Serialize, Deserialize, Debug)]
-#[serde(rename_all = "snake_case")]
-enum Datatype {
-    String,
-    Date,
-    #[serde(alias = "int")]
-    Integer,
-    Float,
+#[derive(Parser, Debug)]
+#[command(version, about)]
+struct Cli {
+    /// Path of the configuration file
+    #[arg(short, long, default_value = "./config.yaml", global = true)]
+    config: PathBuf,
+    /// Override a configuration value, e.g. `--set database.host=db2` (can be repeated)
+    #[arg(long = "set", value_name = "KEY=VALUE", global = true)]
+    overrides: Vec<String>,
+    #[command(subcommand)]
+    command: Option<Command>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct GrowattV6EnergyFragment {
-    name: String,
-    offset: u32,
-    #[serde(alias = "length")]
-    bytes_len: u32,
-    #[serde(alias = "type")]
-    fragment_type: Datatype,
-    fraction: Option<u32>,
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Decodes the messages sent by the dataloggers in a pcap or pcapng capture (e.g. taken with
+    /// tcpdump) instead of running the proxy, e.g. to write the mapping of a new inverter
+    Replay {
+        /// Path of the capture
+        pcap_path: PathBuf,
+        /// Port of the server the dataloggers were sending data to
+        #[arg(long, default_value_t = 5279)]
+        port: u16,
+        /// Also store the decoded messages in the configured database
+        #[arg(long)]
+        store: bool,
+    },
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
-    let db_opts = PgConnectOptions::new()
-        .username("postgres")
-        .password("password")
-        .host("timescale")
-        .port(5432)
-        .database("postgres");
-
-    let db_pool = match PgPool::connect_with(db_opts).await {
-        Ok(pool) => pool,
-        Err(e) => {
-            return Err(
-                Box::try_from(format!("Failed to connect to the Database.\n{}", e)).unwrap(),
-            );
+    let cli = Cli::parse();
+
+    let config = config::load_from_yaml(&cli.config, &cli.overrides)?;
+    if let Err(problems) = config.validate() {
+        // Printed as is, since the returned errors would show the newlines escaped
+        eprintln!("The configuration is not valid:\n{problems}");
+        std::process::exit(1);
+    }
+    let config = Arc::new(config);
+    let mut by_prefix = Vec::new();
+    for (prefix, path) in &config.inverter_mappings {
+        by_prefix.push((prefix.clone(), load_inverter_mapping(path).await?));
+    }
+    let data3_mapping = match &config.data3_mapping {
+        Some(path) => Some(load_inverter_mapping(path).await?),
+        None => None,
+    };
+    let mappings = Arc::new(Mappings::new(
+        load_inverter_mapping(&config.inverters_dir).await?,
+        by_prefix,
+        data3_mapping,
+    ));
+
+    if let Some(Command::Replay {
+        pcap_path,
+        port,
+        store,
+    }) = &cli.command
+    {
+        let storage = match store {
+            true => open_storage(&config).await?.0,
+            false => None,
+        };
+        return replay::replay(pcap_path, *port, &config, &mappings, storage.as_deref()).await;
+    }
+
+    let (storage, db_pool) = open_storage(&config).await?;
+
+    let wide_table = match (&config.wide_table, &db_pool) {
+        (Some(table), Some(db_pool)) => {
+            let wide_table = WideTable::new(db_pool.clone(), table, mappings.fragments())?;
+            if let Err(e) = wide_table.prepare().await {
+                return Err(format!("Failed to prepare the wide table {table}.\n{e}").into());
+            }
+            Some(Arc::new(wide_table))
         }
+        _ => None,
     };
 
-    let json = fs::read_to_string("./inverters/Growatt v6.json").await?;
-    let inverter: Arc<Vec<GrowattV6EnergyFragment>> = Arc::new(serde_json::from_str(&json)?);
+    let spool = match (&storage, &config.database) {
+        (Some(_), Some(database)) => database
+            .spool_path
+            .as_ref()
+            .map(|p| Arc::new(Spool::new(p))),
+        _ => None,
+    };
+
+    let counters = Arc::new(CounterTracker::new(config.counter_fields.clone()));
+    let crc_errors = Arc::new(AtomicU64::new(0));
+    let log_sampler = Arc::new(LogSampler::new(config.logging.sample_rate));
+    let deduplicator = config
+        .dedup_window_secs
+        .map(|secs| Arc::new(Deduplicator::new(Duration::from_secs(secs))));
+
+    #[cfg(feature = "parquet")]
+    let parquet = config
+        .parquet
+        .as_ref()
+        .map(|p| Arc::new(parquet_sink::ParquetSink::new(p, mappings.fragments())));
+    #[cfg(not(feature = "parquet"))]
+    if config.parquet.is_some() {
+        return Err("Parquet storage is configured, but this build does not support it.\n\
+            Rebuild with `--features parquet`, or remove the `parquet` section from the configuration."
+            .into());
+    }
+
+    let health = Arc::new(Health::default());
+    let upstream_tls = upstream_tls(&config)?;
 
     // https://github.com/mqudsi/tcpproxy/blob/master/src/main.rs
-    let listener = match TcpListener::bind("0.0.0.0:5279").await {
+    let listener = match TcpListener::bind(("0.0.0.0", config.listen_port)).await {
         Ok(l) => l,
-        Err(e) => return Err(Box::try_from(format!("Failed to open port 5279: {}", e)).unwrap()),
+        Err(e) => return Err(format!("Failed to open port {}: {}", config.listen_port, e).into()),
     };
     println!("Listening on {}", listener.local_addr().unwrap());
+    health.set_accepting(true);
 
-    let _listener_task: JoinHandle<io::Result<()>> = tokio::spawn(async move {
-        loop {
-            let (client, client_addr) = listener.accept().await?;
+    let shutdown = CancellationToken::new();
+
+    let health_task = config.health_port.map(|port| {
+        let health = health::serve(port, health.clone(), storage.clone(), shutdown.clone());
+        tokio::spawn(async move {
+            if let Err(e) = health.await {
+                eprintln!("The health endpoint on port {port} stopped with an error: {e}");
+            }
+        })
+    });
 
-            let i = inverter.clone();
-            let pool = db_pool.clone();
+    let live_feed = LiveFeed::new(mappings.clone(), shutdown.clone());
 
-            tokio::spawn(async move {
-                let handler = ConnectionHandler {
-                    inverter: i,
-                    db_pool: pool,
-                };
-                if let Err(e) = handler.handle_connection(client, client_addr).await {
-                    eprintln!(
-                        "An error occurred while handling a connection from {}: {}",
-                        client_addr, e
-                    );
+    let spool_task = spool.clone().zip(storage.clone()).map(|(spool, storage)| {
+        let wide_table = wide_table.clone();
+        let instance_id = config.instance_id.clone();
+        let shutdown = shutdown.clone();
+        tokio::spawn(async move {
+            let store = |message: DataMessage| {
+                let storage = storage.clone();
+                let wide_table = wide_table.clone();
+                let instance_id = instance_id.clone();
+                async move {
+                    let id = storage
+                        .store_message(&message, instance_id.as_deref())
+                        .await?;
+                    insert_wide_row(wide_table.as_deref(), id, &message).await;
+                    Ok::<_, sqlx::Error>(())
                 }
-            });
-        }
+            };
+            spool.replay_periodically(store, shutdown).await
+        })
+    });
+
+    #[cfg(feature = "parquet")]
+    let parquet_task = parquet.clone().map(|parquet| {
+        let shutdown = shutdown.clone();
+        tokio::spawn(async move { parquet.flush_periodically(shutdown).await })
     });
 
+    let api_task = config.api_port.map(|port| {
+        let api = api::serve(
+            port,
+            db_pool,
+            mappings.clone(),
+            live_feed.clone(),
+            shutdown.clone(),
+        );
+        tokio::spawn(async move {
+            if let Err(e) = api.await {
+                eprintln!("The HTTP API on port {port} stopped with an error: {e}");
+            }
+        })
+    });
+
+    let handler = ConnectionHandler {
+        mappings,
+        storage,
+        health: health.clone(),
+        upstream_tls,
+        config,
+        counters,
+        wide_table,
+        crc_errors,
+        log_sampler,
+        deduplicator,
+        spool,
+        live_feed,
+        shutdown: shutdown.clone(),
+        #[cfg(feature = "parquet")]
+        parquet: parquet.clone(),
+    };
+    let listener_task = tokio::spawn(accept_connections(listener, handler));
+
     let ctrl_c = async {
         signal::ctrl_c().await.unwrap();
     };
@@ -109,69 +262,583 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
     println!("Received shutdown signal. Stopping.");
 
+    health.set_accepting(false);
+    shutdown.cancel();
+    listener_task.await?;
+    if let Some(api_task) = api_task {
+        api_task.await?;
+    }
+    if let Some(health_task) = health_task {
+        health_task.await?;
+    }
+    if let Some(spool_task) = spool_task {
+        spool_task.await?;
+    }
+
+    #[cfg(feature = "parquet")]
+    if let Some(parquet) = parquet {
+        if let Some(parquet_task) = parquet_task {
+            parquet_task.await?;
+        }
+        if let Err(e) = parquet.flush().await {
+            eprintln!("Error writing the Parquet files, the rows not written are lost: {e}");
+        }
+    }
+
     Ok(())
 }
 
-struct ConnectionHandler {
-    inverter: Arc<Vec<GrowattV6EnergyFragment>>,
-    db_pool: sqlx::Pool<sqlx::Postgres>,
+/// Accepts the connections of the dataloggers until the server shuts down, then gives the open
+/// ones some time to store the messages already received.
+async fn accept_connections(listener: TcpListener, handler: ConnectionHandler) {
+    let config = handler.config.clone();
+    let shutdown = handler.shutdown.clone();
+    let mut connections = JoinSet::new();
+    let connection_limit = config
+        .max_connections
+        .map(|max| Arc::new(Semaphore::new(max)));
+
+    loop {
+        let (client, client_addr) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                // Usually transient (e.g. too many open files, or a client gone before being
+                // accepted): the open connections must not be dropped because of it.
+                Err(e) => {
+                    eprintln!("Could not accept a connection: {e}");
+                    tokio::time::sleep(ACCEPT_ERROR_DELAY).await;
+                    continue;
+                }
+            },
+            // Forgets the connections that are already closed
+            Some(_) = connections.join_next(), if !connections.is_empty() => continue,
+            _ = shutdown.cancelled() => break,
+        };
+
+        // Released when the connection is closed
+        let permit = match &connection_limit {
+            None => None,
+            Some(limit) => match limit.clone().try_acquire_owned() {
+                Ok(permit) => Some(permit),
+                Err(_) if config.reject_when_full => {
+                    eprintln!("Too many connections, rejecting {client_addr}");
+                    continue;
+                }
+                Err(_) => {
+                    eprintln!("Too many connections, {client_addr} waits for one to close");
+                    tokio::select! {
+                        permit = limit.clone().acquire_owned() => permit.ok(),
+                        _ = shutdown.cancelled() => break,
+                    }
+                }
+            },
+        };
+
+        let handler = handler.clone();
+        connections.spawn(async move {
+            let _permit = permit;
+            if let Err(e) = handler.handle_connection(client, client_addr).await {
+                eprintln!(
+                    "An error occurred while handling a connection from {}: {}",
+                    client_addr, e
+                );
+            }
+        });
+    }
+
+    // The connections see the same cancellation: they stop reading, but the messages
+    // already received are still stored.
+    if !connections.is_empty() {
+        println!("Waiting for {} connections to close", connections.len());
+    }
+    let drain = async { while connections.join_next().await.is_some() {} };
+    let timeout = Duration::from_secs(config.shutdown_timeout_secs);
+    if tokio::time::timeout(timeout, drain).await.is_err() {
+        eprintln!(
+            "{} connections did not close within {timeout:?}, aborting them",
+            connections.len()
+        );
+        connections.shutdown().await;
+    }
 }
 
-impl ConnectionHandler {
-    async fn handle_data<'a>(&self, data: &'a [u8]) -> &'a [u8] {
-        let bytes = utils::unscramble_data(data);
+/// The connector and the expected server name, when the upstream is reached over TLS.
+fn upstream_tls(
+    config: &Config,
+) -> Result<Option<(TlsConnector, ServerName<'static>)>, Box<dyn Error>> {
+    if !config.upstream_tls {
+        return Ok(None);
+    }
+
+    let server_name = match &config.upstream_tls_server_name {
+        Some(name) => name.clone(),
+        None => config
+            .remote_address
+            .rsplit_once(':')
+            .map_or(config.remote_address.as_str(), |(host, _)| host)
+            .to_string(),
+    };
+    let server_name = ServerName::try_from(server_name)
+        .map_err(|e| format!("Invalid upstream TLS server name: {e}"))?;
+
+    let mut roots = RootCertStore::empty();
+    roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    let tls_config = ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+
+    Ok(Some((
+        TlsConnector::from(Arc::new(tls_config)),
+        server_name,
+    )))
+}
 
+async fn connect_postgres(config: &DbConfig) -> Result<PgPool, Box<dyn Error>> {
+    let db_opts = match &config.url {
+        Some(url) => {
+            PgConnectOptions::from_str(url).map_err(|e| format!("Invalid database.url: {e}"))?
+        }
+        None => {
+            // Required by the validation of the configuration
+            let (Some(username), Some(password), Some(host), Some(port), Some(database)) = (
+                &config.username,
+                &config.password,
+                &config.host,
+                config.port,
+                &config.database,
+            ) else {
+                return Err("The database connection settings are incomplete".into());
+            };
+            PgConnectOptions::new()
+                .username(username)
+                .password(password)
+                .host(host)
+                .port(port)
+                .database(database)
+        }
+    };
+
+    let mut pool_opts = PgPoolOptions::new();
+    if let Some(max) = config.max_connections {
+        pool_opts = pool_opts.max_connections(max);
+    }
+    if let Some(min) = config.min_connections {
+        pool_opts = pool_opts.min_connections(min);
+    }
+    if let Some(secs) = config.acquire_timeout_secs {
+        pool_opts = pool_opts.acquire_timeout(Duration::from_secs(secs));
+    }
+
+    // The database may still be starting, e.g. when launched together by docker compose.
+    let connect_delay = Duration::from_millis(config.connect_delay_ms);
+    let db_pool = match utils::retry(config.connect_attempts, connect_delay, || async {
+        // A single connection fails right away with the actual error, while the pool would keep
+        // trying until its own timeout.
+        PgConnection::connect_with(&db_opts).await?.close().await?;
+        pool_opts.clone().connect_with(db_opts.clone()).await
+    })
+    .await
+    {
+        Ok(pool) => pool,
+        Err(e) => {
+            return Err(format!("Failed to connect to the Database.\n{}", e).into());
+        }
+    };
+
+    let migrator = sqlx::migrate!();
+    if config.run_migrations {
+        let migrated = utils::retry(config.connect_attempts, connect_delay, || {
+            migrator.run(&db_pool)
+        })
+        .await;
+        if let Err(e) = migrated {
+            return Err(format!("Failed to run the Database migrations.\n{}", e).into());
+        }
+    } else {
+        check_schema_version(&migrator, &db_pool).await?;
+    }
+
+    Ok(db_pool)
+}
+
+/// Connects to the configured database, if any. The pool is returned only for PostgreSQL, since
+/// the wide table and the `/inverters` endpoints of the HTTP API are available only with it.
+async fn open_storage(
+    config: &Config,
+) -> Result<(Option<Arc<dyn Storage>>, Option<PgPool>), Box<dyn Error>> {
+    match &config.database {
+        Some(database) if database.kind == DbKind::Postgres => {
+            let db_pool = connect_postgres(database).await?;
+            Ok((
+                Some(Arc::new(PgStorage::new(db_pool.clone()))),
+                Some(db_pool),
+            ))
+        }
+        database => {
+            if config.wide_table.is_some() {
+                return Err(
+                    "The wide table requires PostgreSQL: \
+                    remove `wide_table` from the configuration, or set `database.kind: postgres`."
+                        .into(),
+                );
+            }
+            if config.api_port.is_some() {
+                println!("The stored data can be queried from the HTTP API only with PostgreSQL, the /inverters endpoints are disabled");
+            }
+            match database {
+                Some(database) => Ok((
+                    Some(Arc::new(SqliteStorage::open(&database.path).await?)),
+                    None,
+                )),
+                None => {
+                    println!("No database configured, the messages will not be stored");
+                    Ok((None, None))
+                }
+            }
+        }
+    }
+}
+
+async fn load_inverter_mapping(path: &str) -> Result<Vec<GrowattV6EnergyFragment>, Box<dyn Error>> {
+    // The existence of the files is checked along with the rest of the configuration.
+    let json = fs::read_to_string(path)
+        .await
+        .map_err(|e| format!("Could not read the inverter mapping file \"{path}\": {e}"))?;
+
+    let mapping: Vec<GrowattV6EnergyFragment> = serde_json::from_str(&json)
+        .map_err(|e| format!("The inverter mapping file \"{path}\" is not valid: {e}"))?;
+
+    mappings::validate(&mapping)
+        .map_err(|e| format!("The inverter mapping file \"{path}\" is not valid:\n{e}"))?;
+
+    Ok(mapping)
+}
+
+/// Also stores the Data4 messages in the wide table, when there is one.
+async fn insert_wide_row(wide_table: Option<&WideTable>, id: i64, datamessage: &DataMessage) {
+    if let (Some(wide_table), MessageType::Data4) = (wide_table, &datamessage.data_type) {
+        if let Err(e) = wide_table.insert(id, datamessage).await {
+            eprintln!("Error inserting the message in the wide table: {e}");
+        }
+    }
+}
+
+/// Used when the migrations are applied externally: makes sure that the schema of the database
+/// is at least at the version expected by this binary.
+async fn check_schema_version(migrator: &Migrator, db_pool: &PgPool) -> Result<(), Box<dyn Error>> {
+    let expected = migrator.iter().map(|m| m.version).max().unwrap_or_default();
+
+    let applied: Option<i64> =
+        sqlx::query_scalar("SELECT MAX(version) FROM _sqlx_migrations WHERE success")
+            .fetch_one(db_pool)
+            .await
+            .map_err(|e| {
+                format!(
+                    "Could not read the schema version from the Database ({e}).\n\
+                    Since `database.run_migrations` is disabled, the migrations in the \"migrations\" \
+                    folder must be applied separately (e.g. with `sqlx migrate run`)."
+                )
+            })?;
+
+    let version = schema_version_matches(applied, expected)?;
+    println!("Database schema is at version {version}, skipping the migrations");
+    Ok(())
+}
+
+/// Checks the version of the last migration applied against the one required by this binary.
+fn schema_version_matches(applied: Option<i64>, expected: i64) -> Result<i64, String> {
+    match applied {
+        Some(version) if version >= expected => Ok(version),
+        applied => Err(format!(
+            "The Database schema is at version {}, but version {expected} is required.\n\
+            Apply the migrations in the \"migrations\" folder (e.g. with `sqlx migrate run`) \
+            or enable `database.run_migrations`.",
+            applied.map_or("none".to_string(), |v| v.to_string())
+        )),
+    }
+}
+
+/// Connects to the mirror (shadow) upstream and sends it a copy of everything the client sends
+/// to the real upstream. Whatever the mirror replies is discarded, and any error on its side
+/// only stops the mirroring, never the proxied connection.
+/// Dropping the returned sender closes the connection to the mirror.
+fn spawn_mirror(address: String, abort: CancellationToken) -> mpsc::Sender<Vec<u8>> {
+    let (tx, mut rx) = mpsc::channel::<Vec<u8>>(64);
+
+    tokio::spawn(async move {
+        let mut mirror = match TcpStream::connect(&address).await {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("Could not connect to the mirror upstream {address}: {e}");
+                return;
+            }
+        };
+
+        let (mut mirror_read, mut mirror_write) = mirror.split();
+        let mut discard = [0u8; 1024];
+
+        loop {
+            tokio::select! {
+                data = rx.recv() => {
+                    let Some(data) = data else { break };
+                    if let Err(e) = mirror_write.write_all(&data).await {
+                        eprintln!("Error writing to the mirror upstream {address}: {e}");
+                        break;
+                    }
+                },
+                read = mirror_read.read(&mut discard) => {
+                    if !matches!(read, Ok(n) if n > 0) {
+                        break;
+                    }
+                },
+                _ = abort.cancelled() => break,
+            }
+        }
+    });
+
+    tx
+}
+
+/// What is forwarded to the datalogger of a message sent by the upstream server: nothing when
+/// its type is blocked, the message itself otherwise.
+fn filter_remote_data(data: &[u8], blocked: &[MessageType]) -> Vec<u8> {
+    // The header is not scrambled, the type can be read directly.
+    let Some(&type_byte) = data.get(7) else {
+        return data.to_vec();
+    };
+
+    let data_type = MessageType::from(type_byte);
+    if blocked.contains(&data_type) {
         println!(
-            "New message! {}",
-            bytes.iter().fold(String::new(), |mut output, b| {
-                write!(output, "{:02x}", b).unwrap();
-                output
-            })
+            "Blocked a {data_type:?} message ({} bytes) sent by the upstream server",
+            data.len()
         );
+        return Vec::new();
+    }
+
+    data.to_vec()
+}
+
+#[derive(Clone)]
+struct ConnectionHandler {
+    mappings: Arc<Mappings>,
+    storage: Option<Arc<dyn Storage>>,
+    health: Arc<Health>,
+    upstream_tls: Option<(TlsConnector, ServerName<'static>)>,
+    config: Arc<Config>,
+    counters: Arc<CounterTracker>,
+    wide_table: Option<Arc<WideTable>>,
+    crc_errors: Arc<AtomicU64>,
+    log_sampler: Arc<LogSampler>,
+    deduplicator: Option<Arc<Deduplicator>>,
+    spool: Option<Arc<Spool>>,
+    live_feed: LiveFeed,
+    /// Cancelled when the server is shutting down
+    shutdown: CancellationToken,
+    #[cfg(feature = "parquet")]
+    parquet: Option<Arc<parquet_sink::ParquetSink>>,
+}
+
+impl ConnectionHandler {
+    /// Handles the messages sent by the upstream server to the datalogger, dropping the ones of
+    /// the blocked types.
+    fn handle_remote_data(&self, data: &[u8]) -> Vec<u8> {
+        if data.len() >= 8 {
+            // Forwarded anyway, the datalogger has its own checks.
+            self.check_crc(data, "the upstream server");
+        }
+
+        filter_remote_data(data, &self.config.blocked_message_types)
+    }
+
+    async fn handle_inverter_data(&self, data: &[u8]) -> Vec<u8> {
+        if data.len() < 8 {
+            eprintln!(
+                "Received {} bytes, too short for a message header. Forwarding without parsing.",
+                data.len()
+            );
+            return data.to_vec();
+        }
+
+        // The message is parsed anyway: better some odd values than losing data while debugging.
+        self.check_crc(data, "the datalogger");
+
+        let mask = self.config.mask.as_deref().map(str::as_bytes);
+        let scrambled = self.is_scrambled(data);
+        let bytes = match scrambled {
+            true => utils::unscramble_data(data, mask),
+            false => data.to_vec(),
+        };
 
         let data_length = u16::from_be_bytes(bytes[4..6].try_into().unwrap());
 
-        println!("Data length: {data_length} bytes");
+        let mut datamessage =
+            match data_message::decode(self.mappings.for_message(&bytes), &bytes, false, None) {
+                Ok(message) => message,
+                Err(e) => {
+                    eprintln!("Could not parse the message, forwarding it anyway: {e}");
+                    return data.to_vec();
+                }
+            };
+
+        if datamessage.data_type == MessageType::Unknown {
+            self.log_unknown(&bytes);
+        }
+
+        if let Some(time_field) = &self.config.time_field {
+            datamessage.use_time_from(time_field);
+        }
+
+        if self.is_duplicate(&bytes, &datamessage) {
+            println!(
+                "Skipping a {:?} message identical to one received less than {}s ago",
+                datamessage.data_type,
+                self.config.dedup_window_secs.unwrap_or_default()
+            );
+            return data.to_vec();
+        }
+
+        self.health.message_received(&datamessage);
+
+        if self
+            .log_sampler
+            .should_log(datamessage.logger_serial().unwrap_or_default())
+        {
+            self.log_message(&bytes, data_length, &datamessage);
+        }
+
+        // The Data3 messages carry past readings, in no particular order
+        if datamessage.data_type == MessageType::Data4 {
+            if let Some(serial) = datamessage.inverter_serial().map(str::to_string) {
+                self.counters.add_deltas(&serial, &mut datamessage.data);
+            }
+        }
+
+        self.live_feed.publish(&datamessage);
+
+        #[cfg(feature = "parquet")]
+        if let Some(parquet) = &self.parquet {
+            if let Err(e) = parquet.push(&datamessage).await {
+                eprintln!("Error writing the message to the Parquet files: {e}");
+            }
+        }
+
+        if let (Some(storage), Some(database)) = (&self.storage, &self.config.database) {
+            self.store(storage.as_ref(), database, &datamessage).await;
+        }
+
+        match scrambled {
+            true => utils::scramble_data(&bytes, mask),
+            false => bytes,
+        }
+    }
+
+    /// Only the data messages are checked, as the pings are identical by design.
+    fn is_duplicate(&self, bytes: &[u8], datamessage: &DataMessage) -> bool {
+        let Some(deduplicator) = &self.deduplicator else {
+            return false;
+        };
+        if !matches!(
+            datamessage.data_type,
+            MessageType::Data3 | MessageType::Data4
+        ) {
+            return false;
+        }
+
+        // Without the header and the CRC, which don't say anything about the readings.
+        let payload = &bytes[8..bytes.len().saturating_sub(2).max(8)];
+        deduplicator.is_duplicate(datamessage.logger_serial().unwrap_or_default(), payload)
+    }
+
+    async fn store(&self, storage: &dyn Storage, database: &DbConfig, datamessage: &DataMessage) {
+        let r = utils::retry(database.insert_attempts, Duration::from_millis(100), || {
+            storage.store_message(datamessage, self.config.instance_id.as_deref())
+        })
+        .await;
 
-        let message = match bytes[7] {
-            0x03 => DataMessage::placeholder(&bytes, MessageType::Data3),
-            0x04 => DataMessage::data4(self.inverter.clone(), &bytes),
-            0x16 => DataMessage::placeholder(&bytes, MessageType::Ping),
-            0x18 => DataMessage::placeholder(&bytes, MessageType::Configure),
-            0x19 => DataMessage::placeholder(&bytes, MessageType::Identify),
-            _ => DataMessage::placeholder(&bytes, MessageType::Unknown),
+        let id = match r {
+            Ok(id) => id,
+            Err(e) => {
+                let Some(spool) = &self.spool else {
+                    eprintln!("Could not store the message: {e}");
+                    return;
+                };
+                match spool.push(datamessage).await {
+                    Ok(()) => eprintln!(
+                        "Could not store the message, keeping it in {} to store it later: {e}",
+                        spool.path().display()
+                    ),
+                    Err(spool_error) => eprintln!(
+                        "Could not store the message ({e}), nor keep it in {}: {spool_error}",
+                        spool.path().display()
+                    ),
+                }
+                return;
+            }
         };
 
-        let datamessage = message.unwrap();
+        insert_wide_row(self.wide_table.as_deref(), id, datamessage).await;
+    }
+
+    fn log_message(&self, bytes: &[u8], data_length: u16, datamessage: &DataMessage) {
+        if self.config.logging.anonymize_serials {
+            // The raw dump would contain the serial numbers in clear.
+            println!("New message! {} bytes", bytes.len());
+        } else {
+            println!(
+                "New message! {}",
+                bytes.iter().fold(String::new(), |mut output, b| {
+                    write!(output, "{:02x}", b).unwrap();
+                    output
+                })
+            );
+        }
 
+        println!("Data length: {data_length} bytes");
         println!("Message type: {:?}", &datamessage.data_type);
 
-        let r = sqlx::query!("INSERT INTO inverter_messages (raw, type, header, time) VALUES ($1, $2, $3, $4) returning id",
-            datamessage.raw, serde_json::to_string(&datamessage.data_type).unwrap(), datamessage.header, datamessage.time)
-            .fetch_one(&self.db_pool)
-            // todo handle unlikely scenarios
-            .await;
+        if let Some(serial) = datamessage.logger_serial() {
+            println!("Datalogger serial: {}", self.log_serial(serial));
+        }
+    }
 
-        if let Err(e) = r {
-            println!("{}", e);
-            return data;
+    /// Dumps the messages of unknown type whatever the sampling, to help figuring them out.
+    fn log_unknown(&self, bytes: &[u8]) {
+        let type_byte = bytes[7];
+        if self.config.logging.anonymize_serials {
+            println!(
+                "Received a message of unknown type 0x{type_byte:02x}, {} bytes",
+                bytes.len()
+            );
+            return;
         }
 
-        let id = r.unwrap().id;
+        let mut dump = String::new();
+        utils::write_hex_dump(&mut dump, bytes, 16).unwrap();
+        println!("Received a message of unknown type 0x{type_byte:02x}:\n{dump}");
+    }
 
-        for (key, value) in datamessage.data {
-            sqlx::query!(
-                "INSERT INTO message_data (message_id, key, value) VALUES ($1, $2, $3)",
-                id,
-                key,
-                value
-            )
-                .execute(&self.db_pool)
-                .await
-                .unwrap();
+    fn log_serial<'a>(&self, serial: &'a str) -> Cow<'a, str> {
+        if self.config.logging.anonymize_serials {
+            Cow::Owned(utils::mask_serial(serial))
+        } else {
+            Cow::Borrowed(serial)
         }
+    }
+
+    fn is_scrambled(&self, data: &[u8]) -> bool {
+        let mask = self.config.mask.as_deref().map(str::as_bytes);
+        !self.config.detect_plaintext || utils::looks_scrambled(data, mask)
+    }
 
-        data
+    /// Logs and counts the messages whose CRC is not valid, in both directions.
+    fn check_crc(&self, data: &[u8], sender: &str) {
+        if !utils::verify_crc16(data) {
+            let errors = self.crc_errors.fetch_add(1, Ordering::Relaxed) + 1;
+            eprintln!(
+                "The CRC of a message sent by {sender} is not valid ({errors} corrupted messages so far)"
+            );
+        }
     }
 
     async fn copy_with_abort<R, W>(
@@ -179,14 +846,17 @@ impl ConnectionHandler {
         read: &mut R,
         write: &mut W,
         abort: CancellationToken,
-        handle_data: bool,
+        from_inverter: bool,
+        mut mirror: Option<mpsc::Sender<Vec<u8>>>,
     ) -> tokio::io::Result<usize>
-        where
-            R: tokio::io::AsyncRead + Unpin,
-            W: tokio::io::AsyncWrite + Unpin,
+    where
+        R: tokio::io::AsyncRead + Unpin,
+        W: tokio::io::AsyncWrite + Unpin,
     {
+        let max_frame_size = self.config.max_frame_size;
         let mut bytes_forwarded = 0;
-        let mut buf = [0u8; BUF_SIZE];
+        let mut buf = vec![0u8; BUF_SIZE];
+        let mut pending = Vec::new();
 
         loop {
             let bytes_read;
@@ -204,19 +874,115 @@ impl ConnectionHandler {
             if bytes_read == 0 {
                 break;
             }
+            pending.extend_from_slice(&buf[..bytes_read]);
 
-            let bytes_to_forward = match handle_data {
-                false => &buf[..bytes_read],
-                true => self.handle_data(&buf[..bytes_read]).await,
-            };
+            // When forwarding fails, the messages already received are still stored before
+            // giving up on the connection.
+            let mut write_error = None;
+            loop {
+                let bytes_to_forward = match utils::next_frame(&mut pending, max_frame_size) {
+                    Ok(Some(frame)) if from_inverter => self.handle_inverter_data(&frame).await,
+                    Ok(Some(frame)) => self.handle_remote_data(&frame),
+                    Ok(None) => break,
+                    // The messages can't be told apart anymore
+                    Err(e) => {
+                        eprintln!("{e}. Forwarding {} bytes without parsing.", pending.len());
+                        std::mem::take(&mut pending)
+                    }
+                };
+                if write_error.is_some() {
+                    continue;
+                }
+
+                if let Err(e) = write.write_all(&bytes_to_forward).await {
+                    write_error = Some(e);
+                    continue;
+                }
+                bytes_forwarded += bytes_to_forward.len();
+
+                // Never slow down the real connection: if the mirror can't keep up, it stops being
+                // mirrored, since a stream with holes could not be split in messages anymore.
+                if let Some(Err(e)) = mirror.as_ref().map(|m| m.try_send(bytes_to_forward)) {
+                    if let mpsc::error::TrySendError::Full(_) = e {
+                        eprintln!(
+                            "The mirror upstream can't keep up, closing the connection to it"
+                        );
+                    }
+                    mirror = None;
+                }
+            }
 
-            write.write_all(bytes_to_forward).await?;
-            bytes_forwarded += bytes_read;
+            if let Some(e) = write_error {
+                return Err(e);
+            }
+        }
+
+        // What is left of an incomplete message is forwarded as is.
+        if !pending.is_empty() {
+            write.write_all(&pending).await?;
+            bytes_forwarded += pending.len();
         }
 
         Ok(bytes_forwarded)
     }
 
+    /// Used instead of the proxy when the upstream is disabled: the messages of the datalogger
+    /// are stored, and acknowledged as the upstream server would.
+    async fn handle_local_connection(
+        &self,
+        client_stream: &mut TcpStream,
+        abort: CancellationToken,
+    ) -> tokio::io::Result<usize> {
+        let mut bytes_received = 0;
+        let mut buf = vec![0u8; BUF_SIZE];
+        let mut pending = Vec::new();
+
+        loop {
+            let bytes_read;
+            tokio::select! {
+                biased;
+
+                result = client_stream.read(&mut buf) => {
+                    bytes_read = result?;
+                },
+                _ = abort.cancelled() => {
+                    break;
+                }
+            }
+
+            if bytes_read == 0 {
+                break;
+            }
+            bytes_received += bytes_read;
+            pending.extend_from_slice(&buf[..bytes_read]);
+
+            loop {
+                let frame = match utils::next_frame(&mut pending, self.config.max_frame_size) {
+                    Ok(Some(frame)) => frame,
+                    Ok(None) => break,
+                    // There is no upstream server to forward them to
+                    Err(e) => {
+                        eprintln!("{e}. Dropping {} bytes.", pending.len());
+                        pending.clear();
+                        break;
+                    }
+                };
+                self.handle_inverter_data(&frame).await;
+
+                let mask = self.config.mask.as_deref().map(str::as_bytes);
+                let ack = frame.get(7).and_then(|&t| {
+                    let scrambled = self.is_scrambled(&frame);
+                    utils::build_ack(MessageType::from(t), &frame, scrambled, mask)
+                });
+                if let Some(ack) = ack {
+                    client_stream.write_all(&ack).await?;
+                }
+            }
+        }
+
+        Ok(bytes_received)
+    }
+
     pub async fn handle_connection(
         &self,
         mut client_stream: TcpStream,
@@ -224,28 +990,78 @@ impl ConnectionHandler {
     ) -> Result<(), Box<dyn Error>> {
         println!("New connection from {}", client_addr);
 
-        let mut remote_server = match TcpStream::connect("server.growatt.com:5279").await {
+        if !self.config.upstream_enabled {
+            let received = self
+                .handle_local_connection(&mut client_stream, self.shutdown.child_token())
+                .await?;
+            eprintln!("Received {received} bytes from client {client_addr} (upstream disabled)");
+            return Ok(());
+        }
+
+        let remote_server = match utils::retry(
+            self.config.upstream_connect_attempts,
+            Duration::from_millis(self.config.upstream_connect_delay_ms),
+            || TcpStream::connect(&self.config.remote_address),
+        )
+        .await
+        {
             Ok(result) => result,
+            Err(e) if self.config.local_fallback => {
+                eprintln!("Error establishing connection: {e}. Storing the messages of {client_addr} locally.");
+                let received = self
+                    .handle_local_connection(&mut client_stream, self.shutdown.child_token())
+                    .await?;
+                eprintln!(
+                    "Received {received} bytes from client {client_addr} (upstream unreachable)"
+                );
+                return Ok(());
+            }
             Err(e) => {
                 eprintln!("Error establishing connection: {e}");
                 return Err(Box::new(e));
             }
         };
 
+        match &self.upstream_tls {
+            Some((connector, server_name)) => {
+                let remote_server = connector
+                    .connect(server_name.clone(), remote_server)
+                    .await
+                    .map_err(|e| format!("TLS handshake with the upstream server failed: {e}"))?;
+                self.proxy(client_stream, client_addr, remote_server).await;
+            }
+            None => self.proxy(client_stream, client_addr, remote_server).await,
+        }
+
+        Ok(())
+    }
+
+    async fn proxy<S>(
+        &self,
+        mut client_stream: TcpStream,
+        client_addr: SocketAddr,
+        remote_server: S,
+    ) where
+        S: tokio::io::AsyncRead + tokio::io::AsyncWrite,
+    {
         let (mut client_read, mut client_write) = client_stream.split();
-        let (mut remote_read, mut remote_write) = remote_server.split();
+        let (mut remote_read, mut remote_write) = tokio::io::split(remote_server);
 
-        let cancellation_token = CancellationToken::new();
+        let cancellation_token = self.shutdown.child_token();
 
         let c3 = cancellation_token.clone();
 
-        // add a wrapping tokio::select! to the tokio join in order to wait for ctrl_c
-        // signal::ctrl_c().await?;
+        let mirror = self
+            .config
+            .mirror_upstream
+            .as_ref()
+            .map(|address| spawn_mirror(address.clone(), cancellation_token.clone()));
+
         let (remote_copied, client_copied) = tokio::join! {
-            self.copy_with_abort(&mut remote_read, &mut client_write, cancellation_token.clone(), false).then(|r| {
+            self.copy_with_abort(&mut remote_read, &mut client_write, cancellation_token.clone(), false, None).then(|r| {
                 c3.cancel(); async {r}
             }),
-            self.copy_with_abort(&mut client_read, &mut remote_write, cancellation_token.clone(), true).then(|r| {
+            self.copy_with_abort(&mut client_read, &mut remote_write, cancellation_token.clone(), true, mirror).then(|r| {
                 c3.cancel(); async {r}
             })
         };
@@ -281,7 +1097,390 @@ impl ConnectionHandler {
                 eprintln!("{}", err);
             }
         };
+    }
+}
 
-        Ok(())
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future::BoxFuture;
+
+    /// A handler with the given configuration, without any storage nor mapping.
+    fn handler(yaml: &str, shutdown: CancellationToken) -> ConnectionHandler {
+        let config: Config = serde_yaml::from_str(yaml).unwrap();
+        let mappings = Arc::new(Mappings::new(Vec::new(), Vec::new(), None));
+
+        ConnectionHandler {
+            mappings: mappings.clone(),
+            storage: None,
+            health: Arc::new(Health::default()),
+            upstream_tls: None,
+            config: Arc::new(config),
+            counters: Arc::new(CounterTracker::new(Vec::new())),
+            wide_table: None,
+            crc_errors: Arc::new(AtomicU64::new(0)),
+            log_sampler: Arc::new(LogSampler::new(1)),
+            deduplicator: None,
+            spool: None,
+            live_feed: LiveFeed::new(mappings, shutdown.clone()),
+            shutdown,
+            #[cfg(feature = "parquet")]
+            parquet: None,
+        }
+    }
+
+    /// A message of the datalogger ABC1234567 with 30 bytes of payload, scrambled
+    fn message(message_type: u8) -> Vec<u8> {
+        let mut message = vec![0x00, 0x01, 0x00, 0x06, 0x00, 0x20, 0x01, message_type];
+        message.extend_from_slice(b"ABC1234567");
+        message.resize(8 + 30, 0);
+        let mut message = utils::scramble_data(&message, None);
+        message.extend_from_slice(&utils::crc16(&message).to_be_bytes());
+        message
+    }
+
+    fn ping() -> Vec<u8> {
+        message(0x16)
+    }
+
+    /// Counts the messages stored, without a database.
+    #[derive(Default)]
+    struct CountingStorage(AtomicU64);
+
+    impl Storage for CountingStorage {
+        fn store_message<'a>(
+            &'a self,
+            _: &'a DataMessage,
+            _: Option<&'a str>,
+        ) -> BoxFuture<'a, Result<i64, sqlx::Error>> {
+            let id = self.0.fetch_add(1, Ordering::Relaxed) as i64;
+            async move { Ok(id) }.boxed()
+        }
+
+        fn ping(&self) -> BoxFuture<'_, Result<(), sqlx::Error>> {
+            async { Ok(()) }.boxed()
+        }
+    }
+
+    #[tokio::test]
+    async fn messages_sent_twice_are_stored_once() {
+        let storage = Arc::new(CountingStorage::default());
+        let mut handler = handler(
+            "database:\n  kind: sqlite\ndedup_window_secs: 60",
+            CancellationToken::new(),
+        );
+        handler.storage = Some(storage.clone());
+        handler.deduplicator = Some(Arc::new(Deduplicator::new(Duration::from_secs(60))));
+
+        let data4 = message(0x04);
+        assert_eq!(handler.handle_inverter_data(&data4).await, data4);
+        assert_eq!(handler.handle_inverter_data(&data4).await, data4);
+
+        assert_eq!(storage.0.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn shutdown_waits_for_the_open_connections() {
+        let shutdown = CancellationToken::new();
+        let handler = handler(
+            "upstream_enabled: false\nshutdown_timeout_secs: 30",
+            shutdown.clone(),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = tokio::spawn(accept_connections(listener, handler));
+
+        // The ping is answered once the connection is being handled
+        let mut client = TcpStream::connect(address).await.unwrap();
+        client.write_all(&ping()).await.unwrap();
+        let mut ack = vec![0; ping().len()];
+        client.read_exact(&mut ack).await.unwrap();
+        assert_eq!(ack, ping());
+
+        shutdown.cancel();
+
+        // Well before the shutdown timeout: the connection stopped by itself
+        tokio::time::timeout(Duration::from_secs(5), server)
+            .await
+            .expect("the server did not stop")
+            .unwrap();
+        assert_eq!(client.read(&mut ack).await.unwrap(), 0);
+    }
+
+    /// Connects a datalogger and checks that its ping is answered.
+    async fn connect_and_ping(address: SocketAddr) -> TcpStream {
+        let mut client = TcpStream::connect(address).await.unwrap();
+        client.write_all(&ping()).await.unwrap();
+        let mut ack = vec![0; ping().len()];
+        client.read_exact(&mut ack).await.unwrap();
+        client
+    }
+
+    #[tokio::test]
+    async fn connections_over_the_limit_are_rejected() {
+        let handler = handler(
+            "upstream_enabled: false\nmax_connections: 1\nreject_when_full: true",
+            CancellationToken::new(),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(accept_connections(listener, handler));
+
+        let first = connect_and_ping(address).await;
+
+        let mut second = TcpStream::connect(address).await.unwrap();
+        let _ = second.write_all(&ping()).await;
+        let mut ack = vec![0; ping().len()];
+        let closed = tokio::time::timeout(Duration::from_secs(5), second.read(&mut ack))
+            .await
+            .expect("the connection was neither answered nor closed");
+        assert!(matches!(closed, Ok(0) | Err(_)), "{closed:?}");
+
+        // Once the first one is closed there is room again, as soon as the server notices
+        drop(first);
+        for attempt in 1.. {
+            let mut third = TcpStream::connect(address).await.unwrap();
+            let _ = third.write_all(&ping()).await;
+            if third.read_exact(&mut ack).await.is_ok() {
+                break;
+            }
+            assert!(attempt < 50, "the connection was never accepted again");
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(ack, ping());
+    }
+
+    #[tokio::test]
+    async fn connections_over_the_limit_wait() {
+        let handler = handler(
+            "upstream_enabled: false\nmax_connections: 1",
+            CancellationToken::new(),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(accept_connections(listener, handler));
+
+        let first = connect_and_ping(address).await;
+
+        let mut second = TcpStream::connect(address).await.unwrap();
+        second.write_all(&ping()).await.unwrap();
+        let mut ack = vec![0; ping().len()];
+        let waiting =
+            tokio::time::timeout(Duration::from_millis(300), second.read_exact(&mut ack)).await;
+        assert!(waiting.is_err(), "the second connection was not held back");
+
+        drop(first);
+        tokio::time::timeout(Duration::from_secs(5), second.read_exact(&mut ack))
+            .await
+            .expect("the second connection is still waiting")
+            .unwrap();
+        assert_eq!(ack, ping());
+    }
+
+    /// An upstream server that can't be written to.
+    struct FailingWriter;
+
+    impl tokio::io::AsyncWrite for FailingWriter {
+        fn poll_write(
+            self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+            _: &[u8],
+        ) -> std::task::Poll<std::io::Result<usize>> {
+            std::task::Poll::Ready(Err(std::io::ErrorKind::BrokenPipe.into()))
+        }
+
+        fn poll_flush(
+            self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(
+            self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn messages_are_stored_when_forwarding_fails() {
+        let storage = Arc::new(CountingStorage::default());
+        let mut handler = handler("database:\n  kind: sqlite", CancellationToken::new());
+        handler.storage = Some(storage.clone());
+
+        // Both in the same read
+        let received = [message(0x03), message(0x04)].concat();
+        let result = handler
+            .copy_with_abort(
+                &mut received.as_slice(),
+                &mut FailingWriter,
+                CancellationToken::new(),
+                true,
+                None,
+            )
+            .await;
+
+        assert_eq!(result.unwrap_err().kind(), std::io::ErrorKind::BrokenPipe);
+        assert_eq!(storage.0.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn messages_with_implausible_lengths_are_forwarded_without_parsing() {
+        let storage = Arc::new(CountingStorage::default());
+        let mut handler = handler(
+            "database:\n  kind: sqlite\nmax_frame_size: 1024",
+            CancellationToken::new(),
+        );
+        handler.storage = Some(storage.clone());
+
+        let mut implausible = message(0x04);
+        implausible[4..6].copy_from_slice(&0xfff0u16.to_be_bytes());
+        let received = [message(0x04), implausible].concat();
+        let mut forwarded = Vec::new();
+        handler
+            .copy_with_abort(
+                &mut received.as_slice(),
+                &mut forwarded,
+                CancellationToken::new(),
+                true,
+                None,
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(forwarded, received);
+        // Only the first one
+        assert_eq!(storage.0.load(Ordering::Relaxed), 1);
+    }
+
+    /// A Configure command (0x18) of the server, scrambled
+    const CONFIGURE: [u8; 14] = [
+        0x00, 0x01, 0x00, 0x06, 0x00, 0x06, 0x01, 0x18, 0x47, 0x72, 0x6f, 0x77, 0xf4, 0xd5,
+    ];
+
+    #[test]
+    fn blocked_types_are_not_forwarded() {
+        let forwarded = filter_remote_data(&CONFIGURE, &[MessageType::Configure]);
+
+        assert!(forwarded.is_empty());
+    }
+
+    #[test]
+    fn the_other_types_are_forwarded_as_they_are() {
+        assert_eq!(
+            filter_remote_data(&CONFIGURE, &[MessageType::Identify]),
+            CONFIGURE
+        );
+        assert_eq!(filter_remote_data(&CONFIGURE, &[]), CONFIGURE);
+        // Too short to have a type
+        assert_eq!(
+            filter_remote_data(&CONFIGURE[..5], &[MessageType::Configure]),
+            &CONFIGURE[..5]
+        );
+    }
+
+    #[test]
+    fn the_migrations_are_skipped_only_with_an_up_to_date_schema() {
+        let expected = sqlx::migrate!().iter().map(|m| m.version).max().unwrap();
+
+        assert_eq!(
+            schema_version_matches(Some(expected), expected),
+            Ok(expected)
+        );
+        // A newer binary may have been rolled back
+        assert_eq!(
+            schema_version_matches(Some(expected + 1), expected),
+            Ok(expected + 1)
+        );
+
+        let error = schema_version_matches(Some(expected - 1), expected).unwrap_err();
+        assert!(error.contains(&format!(
+            "at version {}, but version {expected} is required",
+            expected - 1
+        )));
+        let error = schema_version_matches(None, expected).unwrap_err();
+        assert!(error.contains("at version none"));
+        assert!(error.contains("enable `database.run_migrations`"));
+    }
+
+    /// Accepts the connections and closes them right away, counting them.
+    async fn close_connections(listener: TcpListener, connections: Arc<AtomicU64>) {
+        while let Ok((stream, _)) = listener.accept().await {
+            connections.fetch_add(1, Ordering::Relaxed);
+            drop(stream);
+        }
+    }
+
+    #[tokio::test]
+    async fn the_database_connection_is_attempted_as_configured() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let connections = Arc::new(AtomicU64::new(0));
+        tokio::spawn(close_connections(listener, connections.clone()));
+
+        let config = DbConfig {
+            url: Some(format!(
+                "postgres://growatt:growatt@{address}/growatt?sslmode=disable"
+            )),
+            connect_attempts: 3,
+            connect_delay_ms: 1,
+            ..Default::default()
+        };
+        let error = tokio::time::timeout(Duration::from_secs(10), connect_postgres(&config))
+            .await
+            .expect("the connection was attempted forever")
+            .unwrap_err();
+
+        assert!(error
+            .to_string()
+            .starts_with("Failed to connect to the Database"));
+        assert_eq!(connections.load(Ordering::Relaxed), 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn the_upstream_connection_is_attempted_as_configured() {
+        // Nothing listens on the port once the listener is dropped
+        let closed = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let handler = handler(
+            &format!(
+                "remote_address: {closed}\nupstream_connect_attempts: 3\nupstream_connect_delay_ms: 100"
+            ),
+            CancellationToken::new(),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let _client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, address) = listener.accept().await.unwrap();
+
+        let start = tokio::time::Instant::now();
+        assert!(handler.handle_connection(stream, address).await.is_err());
+
+        // 100ms after the first attempt and 200ms after the second one, then it gives up
+        assert_eq!(start.elapsed(), Duration::from_millis(300));
+    }
+
+    #[tokio::test]
+    async fn mirrored_bytes_reach_the_mirror() {
+        let mirror = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = mirror.local_addr().unwrap().to_string();
+        let abort = CancellationToken::new();
+
+        let sender = spawn_mirror(address, abort.clone());
+        sender.send(b"first".to_vec()).await.unwrap();
+        sender.send(b"second".to_vec()).await.unwrap();
+        // Closes the connection once everything is sent
+        drop(sender);
+
+        let (mut stream, _) = mirror.accept().await.unwrap();
+        let mut received = Vec::new();
+        stream.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"firstsecond");
     }
 }
@@ -0,0 +1,258 @@
+use crate::data_message;
+use crate::types::MessageType;
+use crate::{GrowattV6EnergyFragment, Role};
+
+/// The mappings used to decode the messages, for when different inverter models share the same
+/// proxy: each one is selected by the prefix of the inverter serial number, falling back to
+/// the default mapping.
+/// Data3 messages are decoded like the Data4 ones, unless they have a mapping of their own.
+pub struct Mappings {
+    default: Vec<GrowattV6EnergyFragment>,
+    by_prefix: Vec<(String, Vec<GrowattV6EnergyFragment>)>,
+    data3: Option<Vec<GrowattV6EnergyFragment>>,
+}
+
+impl Mappings {
+    pub fn new(
+        default: Vec<GrowattV6EnergyFragment>,
+        mut by_prefix: Vec<(String, Vec<GrowattV6EnergyFragment>)>,
+        data3: Option<Vec<GrowattV6EnergyFragment>>,
+    ) -> Self {
+        // The most specific prefix wins
+        by_prefix.sort_by_key(|(prefix, _)| std::cmp::Reverse(prefix.len()));
+
+        Self {
+            default,
+            by_prefix,
+            data3,
+        }
+    }
+
+    /// Picks the mapping of an (unscrambled) message. The serial number is read with the
+    /// default mapping, so its position must be the same for all the models.
+    pub fn for_message(&self, bytes: &[u8]) -> &[GrowattV6EnergyFragment] {
+        let message_type = bytes.get(7).copied().map(MessageType::from);
+
+        if let (Some(MessageType::Data3), Some(data3)) = (&message_type, &self.data3) {
+            return data3;
+        }
+        if self.by_prefix.is_empty()
+            || !matches!(message_type, Some(MessageType::Data3 | MessageType::Data4))
+        {
+            return &self.default;
+        }
+
+        let Some(serial) = data_message::peek_inverter_serial(&self.default, bytes) else {
+            return &self.default;
+        };
+
+        self.by_prefix
+            .iter()
+            .find(|(prefix, _)| serial.starts_with(prefix.as_str()))
+            .map_or(&self.default, |(_, mapping)| mapping)
+    }
+
+    /// The fragments of all the mappings, e.g. to know every field that can be decoded.
+    pub fn fragments(&self) -> impl Iterator<Item = &GrowattV6EnergyFragment> {
+        self.default
+            .iter()
+            .chain(self.by_prefix.iter().flat_map(|(_, mapping)| mapping))
+            .chain(self.data3.iter().flatten())
+    }
+}
+
+/// Checks a mapping for the mistakes that would otherwise silently corrupt the decoded data,
+/// returning all the problems found.
+/// Fragments decoding exactly the same bytes are allowed, as aliases of the same value, and so
+/// are overlapping conditional fragments, since they reinterpret the bytes for specific cases.
+pub fn validate(mapping: &[GrowattV6EnergyFragment]) -> Result<(), String> {
+    let mut problems = Vec::new();
+
+    for (i, fragment) in mapping.iter().enumerate() {
+        let range = fragment.offset..fragment.offset + fragment.bytes_len;
+
+        if range.is_empty() {
+            problems.push(format!("{} is 0 bytes long", fragment.name));
+        }
+        // The length field of the header can't describe a longer message
+        if range.end > u32::from(u16::MAX) {
+            problems.push(format!(
+                "{} ends at byte {}, past the end of any message",
+                fragment.name, range.end
+            ));
+        }
+
+        if let Some(condition) = &fragment.when {
+            match mapping.iter().find(|f| f.name == condition.field) {
+                None => problems.push(format!(
+                    "{} depends on {}, which is not in the mapping",
+                    fragment.name, condition.field
+                )),
+                // The conditional fragments are decoded after all the others, in no given order
+                Some(field) if field.when.is_some() => problems.push(format!(
+                    "{} depends on {}, which is conditional too: only unconditional fields can be used",
+                    fragment.name, condition.field
+                )),
+                Some(_) => {}
+            }
+        }
+
+        for other in &mapping[..i] {
+            if other.name == fragment.name {
+                problems.push(format!("{} is defined more than once", fragment.name));
+                continue;
+            }
+
+            let other_range = other.offset..other.offset + other.bytes_len;
+            let overlapping = range.start < other_range.end && other_range.start < range.end;
+            if overlapping
+                && range != other_range
+                && fragment.when.is_none()
+                && other.when.is_none()
+            {
+                problems.push(format!(
+                    "{} (bytes {range:?}) overlaps {} (bytes {other_range:?})",
+                    fragment.name, other.name
+                ));
+            }
+        }
+    }
+
+    let serials = mapping
+        .iter()
+        .filter(|f| f.role == Some(Role::SerialNumber))
+        .count();
+    if serials > 1 {
+        problems.push(format!(
+            "{serials} fragments have the serial_number role, only one can have it"
+        ));
+    }
+
+    match problems.is_empty() {
+        true => Ok(()),
+        false => Err(problems.join("\n")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mapping(json: &str) -> Vec<GrowattV6EnergyFragment> {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn data3_messages_use_their_own_mapping() {
+        let data4 =
+            mapping(r#"[{"name": "Power", "offset": 20, "bytes_len": 2, "fragment_type": "int"}]"#);
+        let data3 =
+            mapping(r#"[{"name": "Power", "offset": 30, "bytes_len": 2, "fragment_type": "int"}]"#);
+        let mappings = Mappings::new(data4, Vec::new(), Some(data3));
+
+        let mut message = vec![0x00, 0x01, 0x00, 0x06, 0x00, 0x20, 0x01, 0x03];
+        assert_eq!(mappings.for_message(&message)[0].offset, 30);
+        message[7] = 0x04;
+        assert_eq!(mappings.for_message(&message)[0].offset, 20);
+    }
+
+    #[test]
+    fn units_are_kept_through_serialization() {
+        let fragments = mapping(
+            r#"[{"name": "PV1Voltage", "offset": 20, "bytes_len": 2, "fragment_type": "float", "fraction": 10, "unit": "V"}]"#,
+        );
+
+        let json = serde_json::to_string(&fragments).unwrap();
+        assert_eq!(mapping(&json)[0].unit.as_deref(), Some("V"));
+
+        let yaml = serde_yaml::to_string(&fragments).unwrap();
+        let fragments: Vec<GrowattV6EnergyFragment> = serde_yaml::from_str(&yaml).unwrap();
+        assert_eq!(fragments[0].unit.as_deref(), Some("V"));
+    }
+
+    #[test]
+    fn valid_mappings_are_accepted() {
+        let mapping = mapping(
+            r#"[
+                {"name": "PV1Voltage", "offset": 20, "bytes_len": 2, "fragment_type": "float", "fraction": 10},
+                {"name": "PV1Current", "offset": 22, "bytes_len": 2, "fragment_type": "float", "fraction": 10}
+            ]"#,
+        );
+
+        assert_eq!(validate(&mapping), Ok(()));
+    }
+
+    #[test]
+    fn overlapping_fragments_are_rejected() {
+        let mapping = mapping(
+            r#"[
+                {"name": "PV1Voltage", "offset": 20, "bytes_len": 2, "fragment_type": "float", "fraction": 10},
+                {"name": "PV1Power", "offset": 21, "bytes_len": 4, "fragment_type": "float", "fraction": 10}
+            ]"#,
+        );
+
+        assert_eq!(
+            validate(&mapping),
+            Err("PV1Power (bytes 21..25) overlaps PV1Voltage (bytes 20..22)".to_string())
+        );
+    }
+
+    #[test]
+    fn alternative_fragments_can_overlap() {
+        let mapping = mapping(
+            r#"[
+                {"name": "Model", "offset": 10, "bytes_len": 1, "fragment_type": "int"},
+                {"name": "PowerA", "offset": 20, "bytes_len": 2, "fragment_type": "int", "when": {"field": "Model", "equals": "1"}},
+                {"name": "PowerB", "offset": 20, "bytes_len": 4, "fragment_type": "int", "when": {"field": "Model", "equals": "2"}}
+            ]"#,
+        );
+
+        assert_eq!(validate(&mapping), Ok(()));
+    }
+
+    #[test]
+    fn duplicate_names_are_rejected() {
+        let mapping = mapping(
+            r#"[
+                {"name": "PV1Voltage", "offset": 20, "bytes_len": 2, "fragment_type": "float", "fraction": 10},
+                {"name": "PV1Voltage", "offset": 30, "bytes_len": 2, "fragment_type": "float", "fraction": 10}
+            ]"#,
+        );
+
+        assert_eq!(
+            validate(&mapping),
+            Err("PV1Voltage is defined more than once".to_string())
+        );
+    }
+
+    #[test]
+    fn conditions_on_unknown_fields_are_rejected() {
+        let mapping = mapping(
+            r#"[
+                {"name": "PowerA", "offset": 20, "bytes_len": 2, "fragment_type": "int", "when": {"field": "Model", "equals": "1"}}
+            ]"#,
+        );
+
+        assert_eq!(
+            validate(&mapping),
+            Err("PowerA depends on Model, which is not in the mapping".to_string())
+        );
+    }
+
+    #[test]
+    fn conditions_on_conditional_fields_are_rejected() {
+        let mapping = mapping(
+            r#"[
+                {"name": "Model", "offset": 10, "bytes_len": 1, "fragment_type": "int"},
+                {"name": "Variant", "offset": 11, "bytes_len": 1, "fragment_type": "int", "when": {"field": "Model", "equals": "1"}},
+                {"name": "PowerA", "offset": 20, "bytes_len": 2, "fragment_type": "int", "when": {"field": "Variant", "equals": "2"}}
+            ]"#,
+        );
+
+        let error = validate(&mapping).unwrap_err();
+        assert!(
+            error.starts_with("PowerA depends on Variant, which is conditional too"),
+            "{error}"
+        );
+    }
+}
@@ -0,0 +1,476 @@
+use crate::config::ParquetConfig;
+use arrow_array::{ArrayRef, RecordBatch, StringArray, TimestampMillisecondArray};
+use arrow_schema::{DataType, Field, Schema, TimeUnit};
+use chrono::{DateTime, NaiveDate, Utc};
+use growatt_server::data_message::DataMessage;
+use growatt_server::types::MessageType;
+use growatt_server::GrowattV6EnergyFragment;
+use parquet::arrow::ArrowWriter;
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio_util::sync::CancellationToken;
+
+/// Longest time between two checks for the partitions to write because of their age
+const MAX_FLUSH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Writes the messages to Parquet files, for offline analysis with tools like DuckDB or pandas.
+///
+/// The files are partitioned Hive-style by UTC date and inverter serial number
+/// (`<directory>/date=2023-12-01/serial=AB12345678/<time>.parquet`), and contain a `time`, `type`
+/// and `serial` column, followed by one text column for every field of the mapping.
+/// The rows of each partition are buffered in memory and a new file is written every `max_rows`
+/// rows, or when the oldest buffered row is older than `max_age_secs`. The rows of a file that
+/// could not be written are kept, and written again with the next ones.
+/// Only the data messages are written, since the other ones have none of the fields.
+pub struct ParquetSink {
+    directory: PathBuf,
+    max_rows: usize,
+    max_age: Duration,
+    schema: Arc<Schema>,
+    fields: Vec<String>,
+    partitions: Mutex<HashMap<(NaiveDate, String), Partition>>,
+}
+
+struct Partition {
+    rows: Vec<Row>,
+    opened: Instant,
+}
+
+struct Row {
+    time: DateTime<Utc>,
+    data_type: String,
+    values: HashMap<String, String>,
+}
+
+impl ParquetSink {
+    pub fn new<'a>(
+        config: &ParquetConfig,
+        mapping: impl IntoIterator<Item = &'a GrowattV6EnergyFragment>,
+    ) -> Self {
+        let mut fields: Vec<String> = Vec::new();
+        for fragment in mapping {
+            if !fields.contains(&fragment.name) {
+                fields.push(fragment.name.clone());
+            }
+        }
+
+        let mut columns = vec![
+            Field::new(
+                "time",
+                DataType::Timestamp(TimeUnit::Millisecond, Some("+00:00".into())),
+                false,
+            ),
+            Field::new("type", DataType::Utf8, false),
+            Field::new("serial", DataType::Utf8, true),
+        ];
+        columns.extend(fields.iter().map(|f| Field::new(f, DataType::Utf8, true)));
+
+        Self {
+            directory: PathBuf::from(&config.directory),
+            max_rows: config.max_rows.max(1),
+            max_age: Duration::from_secs(config.max_age_secs),
+            schema: Arc::new(Schema::new(columns)),
+            fields,
+            partitions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub async fn push(&self, message: &DataMessage) -> Result<(), Box<dyn Error + Send + Sync>> {
+        if !matches!(message.data_type, MessageType::Data3 | MessageType::Data4) {
+            return Ok(());
+        }
+
+        let time = message.time.with_timezone(&Utc);
+        let serial = message.inverter_serial().unwrap_or("unknown").to_string();
+        let key = (time.date_naive(), serial);
+
+        let row = Row {
+            time,
+            data_type: format!("{:?}", message.data_type),
+            values: message.data.clone(),
+        };
+
+        let full = {
+            let mut partitions = self.partitions.lock().unwrap();
+            let partition = partitions.entry(key.clone()).or_insert_with(|| Partition {
+                rows: Vec::new(),
+                opened: Instant::now(),
+            });
+            partition.rows.push(row);
+
+            if partition.rows.len() >= self.max_rows || partition.opened.elapsed() >= self.max_age {
+                partitions.remove_entry(&key)
+            } else {
+                None
+            }
+        };
+
+        match full {
+            Some((key, partition)) => self.write(key, partition).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Writes all the buffered rows, regardless of their number or age.
+    pub async fn flush(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let partitions: Vec<_> = self.partitions.lock().unwrap().drain().collect();
+
+        self.write_all(partitions).await
+    }
+
+    /// Writes the partitions older than `max_age`, which would otherwise wait for the next
+    /// message of their inverter (e.g. the next morning).
+    pub async fn flush_expired(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let expired: Vec<_> = {
+            let mut partitions = self.partitions.lock().unwrap();
+            let keys: Vec<_> = partitions
+                .iter()
+                .filter(|(_, p)| p.opened.elapsed() >= self.max_age)
+                .map(|(key, _)| key.clone())
+                .collect();
+            keys.into_iter()
+                .filter_map(|key| partitions.remove_entry(&key))
+                .collect()
+        };
+
+        self.write_all(expired).await
+    }
+
+    /// Writes every partition, even when some of them fail.
+    async fn write_all(
+        &self,
+        partitions: Vec<((NaiveDate, String), Partition)>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let mut errors = Vec::new();
+
+        for (key, partition) in partitions {
+            if let Err(e) = self.write(key, partition).await {
+                errors.push(e.to_string());
+            }
+        }
+
+        match errors.is_empty() {
+            true => Ok(()),
+            false => Err(errors.join("\n").into()),
+        }
+    }
+
+    /// Calls [`Self::flush_expired`] periodically, until the server shuts down.
+    pub async fn flush_periodically(&self, shutdown: CancellationToken) {
+        let period = self
+            .max_age
+            .clamp(Duration::from_secs(1), MAX_FLUSH_INTERVAL);
+        let mut interval = tokio::time::interval(period);
+
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {},
+                _ = shutdown.cancelled() => return,
+            }
+
+            if let Err(e) = self.flush_expired().await {
+                eprintln!("Error writing the Parquet files: {e}");
+            }
+        }
+    }
+
+    /// Writes the rows of a partition to a new file, putting them back when that fails.
+    async fn write(
+        &self,
+        key: (NaiveDate, String),
+        partition: Partition,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let Err(e) = self.write_file(&key, &partition.rows).await else {
+            return Ok(());
+        };
+
+        let error = format!(
+            "Could not write the {} rows of {} on {}: {e}",
+            partition.rows.len(),
+            key.1,
+            key.0
+        );
+        self.restore(key, partition);
+        Err(error.into())
+    }
+
+    /// Puts back the rows of a partition, before the ones received in the meantime.
+    fn restore(&self, key: (NaiveDate, String), mut partition: Partition) {
+        match self.partitions.lock().unwrap().entry(key) {
+            Entry::Occupied(mut entry) => {
+                partition.rows.append(&mut entry.get_mut().rows);
+                entry.insert(partition);
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(partition);
+            }
+        }
+    }
+
+    async fn write_file(
+        &self,
+        (date, serial): &(NaiveDate, String),
+        rows: &[Row],
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let Some(first) = rows.first() else {
+            return Ok(());
+        };
+
+        let directory = self
+            .directory
+            .join(format!("date={date}"))
+            .join(format!("serial={serial}"));
+        let name = first.time.format("%H%M%S%3f").to_string();
+
+        let batch = self.record_batch(serial, rows)?;
+        let schema = self.schema.clone();
+
+        tokio::task::spawn_blocking(move || -> Result<(), Box<dyn Error + Send + Sync>> {
+            std::fs::create_dir_all(&directory)?;
+            let file = create_new_file(&directory, &name)?;
+            let mut writer = ArrowWriter::try_new(file, schema, None)?;
+            writer.write(&batch)?;
+            writer.close()?;
+            Ok(())
+        })
+        .await??;
+
+        Ok(())
+    }
+
+    fn record_batch(
+        &self,
+        serial: &str,
+        rows: &[Row],
+    ) -> Result<RecordBatch, Box<dyn Error + Send + Sync>> {
+        let mut columns: Vec<ArrayRef> = vec![
+            Arc::new(
+                TimestampMillisecondArray::from_iter_values(
+                    rows.iter().map(|r| r.time.timestamp_millis()),
+                )
+                .with_timezone("+00:00"),
+            ),
+            Arc::new(StringArray::from_iter_values(
+                rows.iter().map(|r| r.data_type.as_str()),
+            )),
+            Arc::new(StringArray::from_iter_values(rows.iter().map(|_| serial))),
+        ];
+
+        for field in &self.fields {
+            columns.push(Arc::new(StringArray::from_iter(
+                rows.iter().map(|r| r.values.get(field).map(String::as_str)),
+            )));
+        }
+
+        Ok(RecordBatch::try_new(self.schema.clone(), columns)?)
+    }
+}
+
+/// Creates `<name>.parquet` in `directory`, or `<name>-1.parquet` and so on when it already exists,
+/// since the inverter clock has a one second resolution and the existing files must never be
+/// overwritten.
+fn create_new_file(directory: &Path, name: &str) -> io::Result<File> {
+    let mut suffix = 0;
+
+    loop {
+        let path = match suffix {
+            0 => directory.join(format!("{name}.parquet")),
+            _ => directory.join(format!("{name}-{suffix}.parquet")),
+        };
+        match File::options().write(true).create_new(true).open(&path) {
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => suffix += 1,
+            file => return file,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow_array::Array;
+    use chrono::Timelike;
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+    const MAPPING: &str = r#"[
+        {"name": "Inverter SN", "offset": 0, "length": 10, "type": "string"},
+        {"name": "PV1 Voltage", "offset": 10, "length": 2, "type": "float", "fraction": 10}
+    ]"#;
+
+    fn data4(mapping: &[GrowattV6EnergyFragment], serial: &str, voltage: u16) -> DataMessage {
+        let mut frame = vec![0x00, 0x01, 0x00, 0x06, 0x00, 14, 0x01, 0x04];
+        frame.extend_from_slice(serial.as_bytes());
+        frame.extend_from_slice(&voltage.to_be_bytes());
+        frame.extend_from_slice(&growatt_server::utils::crc16(&frame).to_be_bytes());
+        DataMessage::data4(mapping, &frame).unwrap()
+    }
+
+    fn sink(name: &str, mapping: &[GrowattV6EnergyFragment]) -> (ParquetSink, PathBuf) {
+        let directory = std::env::temp_dir().join(format!("{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        let config = ParquetConfig {
+            directory: directory.to_string_lossy().into_owned(),
+            max_rows: 100,
+            max_age_secs: 3600,
+        };
+        (ParquetSink::new(&config, mapping), directory)
+    }
+
+    /// The files written for the partitions of a serial number.
+    fn files(directory: &Path, serial: &str) -> Vec<PathBuf> {
+        std::fs::read_dir(directory)
+            .unwrap()
+            .flat_map(|date| {
+                std::fs::read_dir(date.unwrap().path().join(format!("serial={serial}")))
+            })
+            .flatten()
+            .map(|file| file.unwrap().path())
+            .collect()
+    }
+
+    fn column(path: &Path, name: &str) -> Vec<Option<String>> {
+        let reader = ParquetRecordBatchReaderBuilder::try_new(File::open(path).unwrap())
+            .unwrap()
+            .build()
+            .unwrap();
+
+        let mut values = Vec::new();
+        for batch in reader {
+            let batch = batch.unwrap();
+            let column = batch.column_by_name(name).unwrap();
+            let column = column.as_any().downcast_ref::<StringArray>().unwrap();
+            values.extend(
+                (0..column.len()).map(|i| column.is_valid(i).then(|| column.value(i).to_string())),
+            );
+        }
+        values
+    }
+
+    #[tokio::test]
+    async fn written_messages_can_be_read_back() {
+        let mapping: Vec<GrowattV6EnergyFragment> = serde_json::from_str(MAPPING).unwrap();
+        let (sink, directory) = sink("parquet_read_back", &mapping);
+
+        sink.push(&data4(&mapping, "AB12345678", 2305))
+            .await
+            .unwrap();
+        sink.push(&data4(&mapping, "AB12345678", 2311))
+            .await
+            .unwrap();
+        sink.flush().await.unwrap();
+
+        let files = files(&directory, "AB12345678");
+        assert_eq!(files.len(), 1);
+        assert_eq!(
+            column(&files[0], "PV1 Voltage"),
+            [Some("230.5".to_string()), Some("231.1".to_string())]
+        );
+        assert_eq!(
+            column(&files[0], "serial"),
+            vec![Some("AB12345678".to_string()); 2]
+        );
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn files_written_in_the_same_second_are_kept() {
+        let mapping: Vec<GrowattV6EnergyFragment> = serde_json::from_str(MAPPING).unwrap();
+        let (sink, directory) = sink("parquet_same_second", &mapping);
+        let mut first = data4(&mapping, "AB12345678", 2305);
+        let mut second = data4(&mapping, "AB12345678", 2311);
+        // Same time, as read from the clock of the inverter
+        first.time = first.time.with_nanosecond(0).unwrap();
+        second.time = first.time;
+
+        sink.push(&first).await.unwrap();
+        sink.flush().await.unwrap();
+        sink.push(&second).await.unwrap();
+        sink.flush().await.unwrap();
+
+        let files = files(&directory, "AB12345678");
+        assert_eq!(files.len(), 2);
+        let mut values: Vec<_> = files
+            .iter()
+            .flat_map(|file| column(file, "PV1 Voltage"))
+            .collect();
+        values.sort();
+        assert_eq!(
+            values,
+            [Some("230.5".to_string()), Some("231.1".to_string())]
+        );
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn rows_not_written_are_kept() {
+        let mapping: Vec<GrowattV6EnergyFragment> = serde_json::from_str(MAPPING).unwrap();
+        let (sink, directory) = sink("parquet_kept", &mapping);
+        sink.push(&data4(&mapping, "AB12345678", 2305))
+            .await
+            .unwrap();
+        sink.push(&data4(&mapping, "AB12345678", 2311))
+            .await
+            .unwrap();
+
+        // The directory can't be created
+        std::fs::write(&directory, "").unwrap();
+        let error = sink.flush().await.unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("Could not write the 2 rows of AB12345678"));
+
+        std::fs::remove_file(&directory).unwrap();
+        sink.push(&data4(&mapping, "AB12345678", 2320))
+            .await
+            .unwrap();
+        sink.flush().await.unwrap();
+
+        let files = files(&directory, "AB12345678");
+        assert_eq!(files.len(), 1);
+        assert_eq!(
+            column(&files[0], "PV1 Voltage"),
+            [
+                Some("230.5".to_string()),
+                Some("231.1".to_string()),
+                Some("232".to_string())
+            ]
+        );
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn messages_are_partitioned_by_their_utc_date() {
+        let mapping: Vec<GrowattV6EnergyFragment> = serde_json::from_str(MAPPING).unwrap();
+        let (sink, directory) = sink("parquet_utc_date", &mapping);
+        let mut message = data4(&mapping, "AB12345678", 2305);
+        message.time = "2023-12-01T23:30:00Z"
+            .parse::<DateTime<Utc>>()
+            .unwrap()
+            .with_timezone(&chrono::Local);
+
+        sink.push(&message).await.unwrap();
+        sink.flush().await.unwrap();
+
+        assert!(directory
+            .join("date=2023-12-01/serial=AB12345678/233000000.parquet")
+            .exists());
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn pings_are_not_written() {
+        let mapping: Vec<GrowattV6EnergyFragment> = serde_json::from_str(MAPPING).unwrap();
+        let (sink, directory) = sink("parquet_pings", &mapping);
+        let ping = DataMessage::ping(&[0x00, 0x01, 0x00, 0x06, 0x00, 0x02, 0x01, 0x16]).unwrap();
+
+        sink.push(&ping).await.unwrap();
+        sink.flush().await.unwrap();
+
+        assert!(!directory.exists());
+    }
+}
@@ -0,0 +1,648 @@
+use crate::config::Config;
+use crate::storage::Storage;
+use growatt_server::data_message;
+use growatt_server::mappings::Mappings;
+use growatt_server::utils;
+use std::collections::HashMap;
+use std::error::Error;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::Path;
+
+/// Decodes the messages sent by the dataloggers in a pcap or pcapng capture (e.g. taken with
+/// `tcpdump -w`) with the same pipeline of the proxy, printing them and optionally storing them.
+/// Only the traffic sent to `port` is decoded, the replies of the server are ignored.
+pub async fn replay(
+    path: &Path,
+    port: u16,
+    config: &Config,
+    mappings: &Mappings,
+    storage: Option<&dyn Storage>,
+) -> Result<(), Box<dyn Error>> {
+    let capture = tokio::fs::read(path)
+        .await
+        .map_err(|e| format!("Could not read the capture \"{}\": {e}", path.display()))?;
+
+    let streams = tcp_streams(&packets(&capture)?, port);
+    if streams.is_empty() {
+        println!("The capture does not contain any data sent to port {port}");
+    }
+
+    let mask = config.mask.as_deref().map(str::as_bytes);
+    let mut decoded = 0;
+
+    for stream in streams {
+        println!(
+            "Stream from {} to {}: {} bytes",
+            stream.client,
+            stream.server,
+            stream.parts.iter().map(Vec::len).sum::<usize>()
+        );
+        if stream.missing_bytes > 0 {
+            eprintln!(
+                "{} bytes of the stream were not captured, the messages around them are lost",
+                stream.missing_bytes
+            );
+        }
+
+        for frame in frames(stream, config.max_frame_size) {
+            if frame.len() < 8 {
+                eprintln!(
+                    "Skipping {} bytes that are not a valid message",
+                    frame.len()
+                );
+                continue;
+            }
+            // Decoded anyway, like the proxy does
+            if !utils::verify_crc16(&frame) {
+                eprintln!("The CRC of a message is not valid");
+            }
+
+            // Captures may come from any datalogger, whatever the configuration
+            let bytes = match utils::looks_scrambled(&frame, mask) {
+                true => utils::unscramble_data(&frame, mask),
+                false => frame,
+            };
+
+            let mut message =
+                match data_message::decode(mappings.for_message(&bytes), &bytes, false, None) {
+                    Ok(message) => message,
+                    Err(e) => {
+                        eprintln!("Could not parse the message: {e}");
+                        continue;
+                    }
+                };
+
+            if let Some(time_field) = &config.time_field {
+                message.use_time_from(time_field);
+            }
+
+            println!("{message:#?}");
+            decoded += 1;
+
+            if let Some(storage) = storage {
+                if let Err(e) = storage
+                    .store_message(&message, config.instance_id.as_deref())
+                    .await
+                {
+                    eprintln!("Could not store the message: {e}");
+                }
+            }
+        }
+    }
+
+    println!("Decoded {decoded} messages");
+
+    Ok(())
+}
+
+/// Splits the parts of a stream in messages. The parts following a gap, and the first one when
+/// the capture started after the connection, may begin in the middle of a message: the bytes
+/// before the first complete message (with a valid CRC) are skipped.
+fn frames(stream: TcpStream, max_frame_size: usize) -> Vec<Vec<u8>> {
+    let mut frames = Vec::new();
+
+    for (i, mut pending) in stream.parts.into_iter().enumerate() {
+        if i > 0 || !stream.aligned {
+            let skipped = first_message(&pending, max_frame_size).unwrap_or(pending.len());
+            if skipped > 0 {
+                eprintln!("Skipping {skipped} bytes of a message that was not fully captured");
+                pending.drain(..skipped);
+            }
+        }
+
+        loop {
+            match utils::next_frame(&mut pending, max_frame_size) {
+                Ok(Some(frame)) => frames.push(frame),
+                Ok(None) => break,
+                Err(e) => {
+                    eprintln!("{e}. Skipping {} bytes.", pending.len());
+                    pending.clear();
+                    break;
+                }
+            }
+        }
+
+        if !pending.is_empty() {
+            eprintln!(
+                "Skipping {} bytes of a message that was not fully captured",
+                pending.len()
+            );
+        }
+    }
+
+    frames
+}
+
+/// Finds where the first complete message of `data` starts.
+fn first_message(data: &[u8], max_frame_size: usize) -> Option<usize> {
+    (0..data.len()).find(|&start| {
+        let Some(length) = data.get(start + 4..start + 6) else {
+            return false;
+        };
+        let frame_size = 6 + u16::from_be_bytes([length[0], length[1]]) as usize + 2;
+
+        frame_size <= max_frame_size
+            && data
+                .get(start..start + frame_size)
+                .is_some_and(utils::verify_crc16)
+    })
+}
+
+const PCAP_MAGIC: u32 = 0xa1b2c3d4;
+const PCAP_MAGIC_NANOS: u32 = 0xa1b23c4d;
+const PCAPNG_SECTION_HEADER: u32 = 0x0a0d0d0a;
+const PCAPNG_BYTE_ORDER_MAGIC: u32 = 0x1a2b3c4d;
+
+/// A captured packet, starting from the link layer header.
+struct Packet<'a> {
+    link_type: u32,
+    data: &'a [u8],
+}
+
+/// Reads the fields of the capture files, which are written in the byte order of the machine
+/// that took the capture.
+#[derive(Clone, Copy)]
+struct Reader {
+    big_endian: bool,
+}
+
+impl Reader {
+    fn u16(self, bytes: &[u8], offset: usize) -> Option<u16> {
+        let bytes = bytes.get(offset..offset + 2)?.try_into().ok()?;
+        Some(match self.big_endian {
+            true => u16::from_be_bytes(bytes),
+            false => u16::from_le_bytes(bytes),
+        })
+    }
+
+    fn u32(self, bytes: &[u8], offset: usize) -> Option<u32> {
+        let bytes = bytes.get(offset..offset + 4)?.try_into().ok()?;
+        Some(match self.big_endian {
+            true => u32::from_be_bytes(bytes),
+            false => u32::from_le_bytes(bytes),
+        })
+    }
+}
+
+/// Splits a capture in its packets, telling apart the pcap and pcapng formats by their magic.
+fn packets(capture: &[u8]) -> Result<Vec<Packet<'_>>, String> {
+    let magic = capture
+        .get(0..4)
+        .ok_or("The capture is empty")?
+        .try_into()
+        .unwrap();
+
+    if u32::from_le_bytes(magic) == PCAPNG_SECTION_HEADER {
+        return pcapng_packets(capture);
+    }
+
+    let big_endian = match (u32::from_be_bytes(magic), u32::from_le_bytes(magic)) {
+        (PCAP_MAGIC | PCAP_MAGIC_NANOS, _) => true,
+        (_, PCAP_MAGIC | PCAP_MAGIC_NANOS) => false,
+        _ => return Err("The file is neither a pcap nor a pcapng capture".to_string()),
+    };
+    let reader = Reader { big_endian };
+
+    let link_type = reader
+        .u32(capture, 20)
+        .ok_or("The pcap capture is too short for its header")?;
+
+    // 24 bytes of file header, then every packet has a 16 bytes header with its length.
+    let mut packets = Vec::new();
+    let mut offset = 24;
+    while offset < capture.len() {
+        let data = reader
+            .u32(capture, offset + 8)
+            .and_then(|len| capture.get(offset + 16..offset + 16 + len as usize))
+            .ok_or("The pcap capture is truncated")?;
+
+        packets.push(Packet { link_type, data });
+        offset += 16 + data.len();
+    }
+
+    Ok(packets)
+}
+
+/// pcapng captures are sequences of blocks, each starting with its type and length. The packets
+/// refer to the interface they were captured on, which defines their link type.
+fn pcapng_packets(capture: &[u8]) -> Result<Vec<Packet<'_>>, String> {
+    let mut packets = Vec::new();
+    let mut reader = Reader { big_endian: false };
+    let mut interfaces = Vec::new();
+    let mut offset = 0;
+
+    while offset < capture.len() {
+        let block = &capture[offset..];
+
+        // The byte order is defined by each section, its header type reads the same in both.
+        if reader.u32(block, 0) == Some(PCAPNG_SECTION_HEADER) {
+            reader.big_endian =
+                block.get(8..12) == Some(PCAPNG_BYTE_ORDER_MAGIC.to_be_bytes().as_slice());
+            interfaces.clear();
+        }
+
+        let (Some(block_type), Some(len)) = (reader.u32(block, 0), reader.u32(block, 4)) else {
+            return Err("The pcapng capture is truncated".to_string());
+        };
+        let block = match block.get(..len as usize) {
+            Some(block) if len >= 12 => block,
+            _ => return Err("The pcapng capture is truncated".to_string()),
+        };
+
+        match block_type {
+            // Interface description
+            1 => interfaces.push(u32::from(reader.u16(block, 8).unwrap_or_default())),
+            // Enhanced packet: interface, timestamp, captured and original length, then the data
+            6 => {
+                let link_type = reader
+                    .u32(block, 8)
+                    .and_then(|interface| interfaces.get(interface as usize));
+                let data = reader
+                    .u32(block, 20)
+                    .and_then(|len| block.get(28..28 + len as usize));
+
+                match (link_type, data) {
+                    (Some(&link_type), Some(data)) => packets.push(Packet { link_type, data }),
+                    _ => eprintln!("Skipping a malformed packet at byte {offset} of the capture"),
+                }
+            }
+            // Simple packet: the original length, then the data, of the first interface
+            3 => {
+                let data = reader
+                    .u32(block, 8)
+                    .and_then(|len| block.get(12..(12 + len as usize).min(block.len() - 4)));
+
+                if let (Some(&link_type), Some(data)) = (interfaces.first(), data) {
+                    packets.push(Packet { link_type, data });
+                }
+            }
+            _ => {}
+        }
+
+        offset += block.len();
+    }
+
+    Ok(packets)
+}
+
+/// The bytes sent by a client over a TCP connection, in order.
+struct TcpStream {
+    client: SocketAddr,
+    server: SocketAddr,
+    /// Sequence number of the first byte of the stream, when the capture has the SYN
+    start: Option<u32>,
+    segments: Vec<(u32, Vec<u8>)>,
+    /// The contiguous runs of bytes, split where some segments were not captured
+    parts: Vec<Vec<u8>>,
+    /// Whether the first part starts with the connection, and so with a message
+    aligned: bool,
+    missing_bytes: usize,
+}
+
+impl TcpStream {
+    fn new(client: SocketAddr, server: SocketAddr, start: Option<u32>) -> Self {
+        Self {
+            client,
+            server,
+            start,
+            segments: Vec::new(),
+            parts: Vec::new(),
+            aligned: start.is_some(),
+            missing_bytes: 0,
+        }
+    }
+
+    /// Orders the segments by their sequence number, skipping the retransmitted bytes.
+    fn reassemble(&mut self) {
+        let Some(start) = self
+            .start
+            .or_else(|| self.segments.iter().map(|(seq, _)| *seq).min())
+        else {
+            return;
+        };
+        // Offset in the stream of the end of the last part
+        let mut end = 0;
+
+        // Relative to the start, so that the sequence numbers wrapping around don't matter
+        self.segments
+            .sort_by_key(|(seq, _)| seq.wrapping_sub(start));
+
+        for (seq, segment) in self.segments.drain(..) {
+            let offset = seq.wrapping_sub(start) as usize;
+            // Sent before the start of the stream, e.g. a retransmitted SYN
+            if offset >= 1 << 31 {
+                continue;
+            }
+            if offset > end || self.parts.is_empty() {
+                self.missing_bytes += offset - end;
+                // The first part doesn't start with the connection either
+                self.aligned &= offset == 0;
+                self.parts.push(Vec::new());
+                end = offset;
+            }
+
+            let new_bytes = segment.get(end - offset..).unwrap_or_default();
+            end += new_bytes.len();
+            self.parts.last_mut().unwrap().extend_from_slice(new_bytes);
+        }
+    }
+}
+
+const TCP: u8 = 6;
+const TCP_SYN: u8 = 0x02;
+
+/// Rebuilds the data sent to `port` by each connection of the capture, in order of connection.
+fn tcp_streams(packets: &[Packet], port: u16) -> Vec<TcpStream> {
+    let mut streams: Vec<TcpStream> = Vec::new();
+    let mut open: HashMap<(SocketAddr, SocketAddr), usize> = HashMap::new();
+
+    for packet in packets {
+        let Some((source, destination, tcp)) = link_payload(packet).and_then(ip_payload) else {
+            continue;
+        };
+        let (Some(ports), Some(seq), Some(&header_len), Some(&flags)) =
+            (tcp.get(0..4), tcp.get(4..8), tcp.get(12), tcp.get(13))
+        else {
+            continue;
+        };
+
+        let client = SocketAddr::new(source, u16::from_be_bytes([ports[0], ports[1]]));
+        let server = SocketAddr::new(destination, u16::from_be_bytes([ports[2], ports[3]]));
+        if server.port() != port {
+            continue;
+        }
+
+        let seq = u32::from_be_bytes(seq.try_into().unwrap());
+        let data = tcp
+            .get(usize::from(header_len >> 4) * 4..)
+            .unwrap_or_default();
+
+        // A new connection may reuse the ports of a previous one.
+        if flags & TCP_SYN != 0 {
+            open.insert((client, server), streams.len());
+            streams.push(TcpStream::new(client, server, Some(seq.wrapping_add(1))));
+            continue;
+        }
+        if data.is_empty() {
+            continue;
+        }
+
+        // The capture may start when the connection is already open.
+        let index = *open.entry((client, server)).or_insert_with(|| {
+            streams.push(TcpStream::new(client, server, None));
+            streams.len() - 1
+        });
+        streams[index].segments.push((seq, data.to_vec()));
+    }
+
+    for stream in &mut streams {
+        stream.reassemble();
+    }
+    streams.retain(|stream| !stream.parts.is_empty());
+
+    streams
+}
+
+/// Strips the link layer header, for the link types tcpdump uses on Linux.
+fn link_payload<'a>(packet: &Packet<'a>) -> Option<&'a [u8]> {
+    let data = packet.data;
+    match packet.link_type {
+        // Ethernet, possibly with a VLAN tag
+        1 => match data.get(12..14)? {
+            [0x81, 0x00] => data.get(18..),
+            _ => data.get(14..),
+        },
+        // BSD loopback
+        0 => data.get(4..),
+        // Raw IP
+        12 | 101 | 228 | 229 => Some(data),
+        // Linux cooked capture (`tcpdump -i any`), v1 and v2
+        113 => data.get(16..),
+        276 => data.get(20..),
+        _ => None,
+    }
+}
+
+/// Reads the addresses and the TCP segment of an IPv4 or IPv6 packet.
+/// Fragmented packets and IPv6 extension headers are not supported.
+fn ip_payload(ip: &[u8]) -> Option<(IpAddr, IpAddr, &[u8])> {
+    match ip.first()? >> 4 {
+        4 => {
+            let header_len = usize::from(ip[0] & 0x0f) * 4;
+            let total_len = usize::from(u16::from_be_bytes(ip.get(2..4)?.try_into().ok()?));
+            if *ip.get(9)? != TCP {
+                return None;
+            }
+
+            let source: [u8; 4] = ip.get(12..16)?.try_into().ok()?;
+            let destination: [u8; 4] = ip.get(16..20)?.try_into().ok()?;
+            // Short frames are padded by Ethernet
+            let tcp = ip.get(header_len..total_len.min(ip.len()))?;
+
+            Some((
+                Ipv4Addr::from(source).into(),
+                Ipv4Addr::from(destination).into(),
+                tcp,
+            ))
+        }
+        6 => {
+            let payload_len = usize::from(u16::from_be_bytes(ip.get(4..6)?.try_into().ok()?));
+            if *ip.get(6)? != TCP {
+                return None;
+            }
+
+            let source: [u8; 16] = ip.get(8..24)?.try_into().ok()?;
+            let destination: [u8; 16] = ip.get(24..40)?.try_into().ok()?;
+            let tcp = ip.get(40..(40 + payload_len).min(ip.len()))?;
+
+            Some((
+                Ipv6Addr::from(source).into(),
+                Ipv6Addr::from(destination).into(),
+                tcp,
+            ))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PORT: u16 = 5279;
+    const MAX_FRAME_SIZE: usize = 65535;
+
+    /// A ping of the datalogger with the given serial number, 40 bytes long.
+    fn message(serial: &str) -> Vec<u8> {
+        let mut frame = vec![0x00, 0x01, 0x00, 0x06, 0x00, 0x20, 0x01, 0x16];
+        frame.extend_from_slice(format!("{serial:\0<30}").as_bytes());
+        let mut frame = utils::scramble_data(&frame, None);
+        frame.extend_from_slice(&utils::crc16(&frame).to_be_bytes());
+        frame
+    }
+
+    /// An Ethernet frame with a TCP segment sent from 192.168.1.50:40000 to `port`.
+    fn packet(port: u16, seq: u32, flags: u8, payload: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0; 12];
+        packet.extend_from_slice(&[0x08, 0x00]);
+
+        packet.extend_from_slice(&[0x45, 0x00]);
+        packet.extend_from_slice(&(40 + payload.len() as u16).to_be_bytes());
+        packet.extend_from_slice(&[0, 0, 0, 0, 64, TCP, 0, 0]);
+        packet.extend_from_slice(&[192, 168, 1, 50, 192, 168, 1, 1]);
+
+        packet.extend_from_slice(&40000u16.to_be_bytes());
+        packet.extend_from_slice(&port.to_be_bytes());
+        packet.extend_from_slice(&seq.to_be_bytes());
+        packet.extend_from_slice(&[0, 0, 0, 0, 0x50, flags, 0xff, 0xff, 0, 0, 0, 0]);
+        packet.extend_from_slice(payload);
+        packet
+    }
+
+    fn pcap(packets: &[Vec<u8>]) -> Vec<u8> {
+        let mut capture = PCAP_MAGIC.to_le_bytes().to_vec();
+        capture.extend_from_slice(&[2, 0, 4, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, 0, 0]);
+        capture.extend_from_slice(&1u32.to_le_bytes());
+
+        for packet in packets {
+            capture.extend_from_slice(&[0; 8]);
+            capture.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+            capture.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+            capture.extend_from_slice(packet);
+        }
+        capture
+    }
+
+    fn pcapng(packets: &[Vec<u8>]) -> Vec<u8> {
+        fn block(capture: &mut Vec<u8>, block_type: u32, body: &[u8]) {
+            let padding = (4 - body.len() % 4) % 4;
+            let len = (12 + body.len() + padding) as u32;
+            capture.extend_from_slice(&block_type.to_be_bytes());
+            capture.extend_from_slice(&len.to_be_bytes());
+            capture.extend_from_slice(body);
+            capture.extend_from_slice(&vec![0; padding]);
+            capture.extend_from_slice(&len.to_be_bytes());
+        }
+
+        // Big-endian, unlike the pcap fixtures
+        let mut capture = Vec::new();
+        let mut section = PCAPNG_BYTE_ORDER_MAGIC.to_be_bytes().to_vec();
+        section.extend_from_slice(&[0, 1, 0, 0, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]);
+        block(&mut capture, PCAPNG_SECTION_HEADER, &section);
+        block(&mut capture, 1, &[0, 1, 0, 0, 0, 0, 0xff, 0xff]);
+
+        for packet in packets {
+            let mut body = vec![0; 12];
+            body.extend_from_slice(&(packet.len() as u32).to_be_bytes());
+            body.extend_from_slice(&(packet.len() as u32).to_be_bytes());
+            body.extend_from_slice(packet);
+            block(&mut capture, 6, &body);
+        }
+        capture
+    }
+
+    fn frames_of(capture: &[u8]) -> Vec<Vec<u8>> {
+        let mut streams = tcp_streams(&packets(capture).unwrap(), PORT);
+        assert_eq!(streams.len(), 1);
+        frames(streams.remove(0), MAX_FRAME_SIZE)
+    }
+
+    #[test]
+    fn pcap_captures_are_read() {
+        let (first, second) = (message("ABC1234567"), message("DEF1234567"));
+        let capture = pcap(&[
+            packet(PORT, 1000, TCP_SYN, &[]),
+            packet(PORT, 1001, 0x18, &first),
+            packet(PORT, 1041, 0x18, &second),
+        ]);
+
+        let streams = tcp_streams(&packets(&capture).unwrap(), PORT);
+        assert_eq!(streams[0].client.to_string(), "192.168.1.50:40000");
+        assert_eq!(streams[0].server.to_string(), "192.168.1.1:5279");
+        assert_eq!(frames_of(&capture), [first, second]);
+    }
+
+    #[test]
+    fn pcapng_captures_are_read() {
+        let (first, second) = (message("ABC1234567"), message("DEF1234567"));
+        let capture = pcapng(&[
+            packet(PORT, 1000, TCP_SYN, &[]),
+            packet(PORT, 1001, 0x18, &first),
+            // Another service, ignored
+            packet(PORT + 1, 1001, 0x18, &second),
+            packet(PORT, 1041, 0x18, &second),
+        ]);
+
+        assert_eq!(frames_of(&capture), [first, second]);
+    }
+
+    #[test]
+    fn out_of_order_segments_are_reordered() {
+        let (first, second) = (message("ABC1234567"), message("DEF1234567"));
+        let capture = pcap(&[
+            packet(PORT, 1000, TCP_SYN, &[]),
+            packet(PORT, 1041, 0x18, &second),
+            packet(PORT, 1001, 0x18, &first),
+        ]);
+
+        assert_eq!(frames_of(&capture), [first, second]);
+    }
+
+    #[test]
+    fn retransmitted_bytes_are_read_once() {
+        let (first, second) = (message("ABC1234567"), message("DEF1234567"));
+        let both = [first.clone(), second.clone()].concat();
+        let capture = pcap(&[
+            packet(PORT, 1000, TCP_SYN, &[]),
+            packet(PORT, 1001, 0x18, &first),
+            packet(PORT, 1001, 0x18, &first),
+            // Retransmitted along with the new bytes
+            packet(PORT, 1021, 0x18, &both[20..]),
+        ]);
+
+        assert_eq!(frames_of(&capture), [first, second]);
+    }
+
+    #[test]
+    fn messages_not_fully_captured_are_skipped() {
+        let (first, second, third) = (
+            message("ABC1234567"),
+            message("DEF1234567"),
+            message("GHI1234567"),
+        );
+        let capture = pcap(&[
+            packet(PORT, 1000, TCP_SYN, &[]),
+            packet(PORT, 1001, 0x18, &first),
+            // The first half of the second message is lost
+            packet(
+                PORT,
+                1061,
+                0x18,
+                &[&second[20..], third.as_slice()].concat(),
+            ),
+        ]);
+
+        let mut streams = tcp_streams(&packets(&capture).unwrap(), PORT);
+        assert_eq!(streams[0].missing_bytes, 20);
+        assert_eq!(frames(streams.remove(0), MAX_FRAME_SIZE), [first, third]);
+    }
+
+    #[test]
+    fn captures_can_start_after_the_connection() {
+        let (first, second) = (message("ABC1234567"), message("DEF1234567"));
+        let capture = pcap(&[packet(
+            PORT,
+            1021,
+            0x18,
+            &[&first[20..], second.as_slice()].concat(),
+        )]);
+
+        assert_eq!(frames_of(&capture), [second]);
+    }
+
+    #[test]
+    fn other_files_are_rejected() {
+        assert!(packets(b"not a capture").is_err());
+        assert!(packets(&pcap(&[message("ABC1234567")])[..30]).is_err());
+    }
+}
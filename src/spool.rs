@@ -0,0 +1,225 @@
+use growatt_server::DataMessage;
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+use tokio_util::sync::CancellationToken;
+
+/// Time between two attempts to store the spooled messages
+const REPLAY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Keeps the messages that could not be stored (e.g. while the database is down) in a JSON Lines
+/// file, one message per line, so that they can be stored once the database is back.
+pub struct Spool {
+    path: PathBuf,
+    /// Held while the file is written, so that the messages added during a replay are not lost
+    lock: Mutex<()>,
+}
+
+impl Spool {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            lock: Mutex::new(()),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub async fn push(&self, message: &DataMessage) -> io::Result<()> {
+        let mut line = serde_json::to_string(message)?;
+        line.push('\n');
+
+        let _lock = self.lock.lock().await;
+        fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await?
+            .write_all(line.as_bytes())
+            .await
+    }
+
+    /// Stores the spooled messages in order, stopping at the first one that can't be stored:
+    /// that one and the following are kept for the next replay.
+    /// Returns the number of messages stored.
+    pub async fn replay<F, Fut, E>(&self, mut store: F) -> io::Result<usize>
+    where
+        F: FnMut(DataMessage) -> Fut,
+        Fut: Future<Output = Result<(), E>>,
+        E: fmt::Display,
+    {
+        let _lock = self.lock.lock().await;
+
+        let content = match fs::read_to_string(&self.path).await {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+            content => content?,
+        };
+
+        let mut stored = 0;
+        let mut lines = content.lines();
+        let mut kept = String::new();
+
+        for line in lines.by_ref() {
+            let message: DataMessage = match serde_json::from_str(line) {
+                Ok(message) => message,
+                Err(e) => {
+                    eprintln!(
+                        "Dropping a message of {} that can't be read: {e}",
+                        self.path.display()
+                    );
+                    continue;
+                }
+            };
+
+            if let Err(e) = store(message).await {
+                eprintln!(
+                    "Could not store the messages of {}, retrying later: {e}",
+                    self.path.display()
+                );
+                kept.push_str(line);
+                kept.push('\n');
+                break;
+            }
+            stored += 1;
+        }
+
+        for line in lines {
+            kept.push_str(line);
+            kept.push('\n');
+        }
+
+        if kept.is_empty() {
+            fs::remove_file(&self.path).await?;
+        } else if stored > 0 {
+            // Replaced at once, so that a crash can't leave a half-written file
+            let temporary = self.path.with_extension("tmp");
+            fs::write(&temporary, kept).await?;
+            fs::rename(&temporary, &self.path).await?;
+        }
+
+        Ok(stored)
+    }
+
+    /// Calls [`Self::replay`] right away and then periodically, until the server shuts down.
+    pub async fn replay_periodically<F, Fut, E>(&self, mut store: F, shutdown: CancellationToken)
+    where
+        F: FnMut(DataMessage) -> Fut,
+        Fut: Future<Output = Result<(), E>>,
+        E: fmt::Display,
+    {
+        let mut interval = tokio::time::interval(REPLAY_INTERVAL);
+
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {},
+                _ = shutdown.cancelled() => return,
+            }
+
+            match self.replay(&mut store).await {
+                Ok(0) => {}
+                Ok(stored) => println!(
+                    "Stored {stored} messages kept in {} while they could not be stored",
+                    self.path.display()
+                ),
+                Err(e) => eprintln!("Error reading {}: {e}", self.path.display()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use growatt_server::types::MessageType;
+
+    fn message(serial: &str) -> DataMessage {
+        let mut frame = vec![0x00, 0x01, 0x00, 0x06, 0x00, 0x20, 0x01, 0x16];
+        frame.extend_from_slice(format!("{serial:\0<30}").as_bytes());
+        frame.extend_from_slice(&growatt_server::utils::crc16(&frame).to_be_bytes());
+        DataMessage::ping(&frame).unwrap()
+    }
+
+    fn spool(name: &str) -> Spool {
+        let path = std::env::temp_dir().join(format!("{name}-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        Spool::new(path)
+    }
+
+    #[tokio::test]
+    async fn spooled_messages_are_stored_once_possible() {
+        let spool = spool("spool_stored");
+        spool.push(&message("ABC1234567")).await.unwrap();
+        spool.push(&message("XYZ7654321")).await.unwrap();
+
+        // The database is still down
+        let stored = spool
+            .replay(|_| async { Err("connection refused") })
+            .await
+            .unwrap();
+        assert_eq!(stored, 0);
+        assert!(spool.path().exists());
+
+        let mut serials = Vec::new();
+        let stored = spool
+            .replay(|message| {
+                assert!(matches!(message.data_type, MessageType::Ping));
+                serials.push(message.logger_serial().unwrap().to_string());
+                async { Ok::<_, String>(()) }
+            })
+            .await
+            .unwrap();
+        assert_eq!(stored, 2);
+        assert_eq!(serials, ["ABC1234567", "XYZ7654321"]);
+        assert!(!spool.path().exists());
+    }
+
+    #[tokio::test]
+    async fn the_messages_after_a_failure_are_kept() {
+        let spool = spool("spool_kept");
+        for serial in ["ABC1234567", "DEF1234567", "GHI1234567"] {
+            spool.push(&message(serial)).await.unwrap();
+        }
+
+        let mut attempts = 0;
+        let stored = spool
+            .replay(|_| {
+                attempts += 1;
+                let result = match attempts {
+                    1 => Ok(()),
+                    _ => Err("connection lost"),
+                };
+                async move { result }
+            })
+            .await
+            .unwrap();
+        assert_eq!((stored, attempts), (1, 2));
+
+        let mut serials = Vec::new();
+        spool
+            .replay(|message| {
+                serials.push(message.logger_serial().unwrap().to_string());
+                async { Ok::<_, String>(()) }
+            })
+            .await
+            .unwrap();
+        assert_eq!(serials, ["DEF1234567", "GHI1234567"]);
+    }
+
+    #[tokio::test]
+    async fn nothing_to_replay_without_the_file() {
+        let spool = spool("spool_missing");
+
+        let stored = spool
+            .replay(|_| async { Ok::<_, String>(()) })
+            .await
+            .unwrap();
+        assert_eq!(stored, 0);
+    }
+}
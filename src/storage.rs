@@ -0,0 +1,239 @@
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use growatt_server::data_message::DataMessage;
+use sqlx::sqlite::SqliteConnectOptions;
+use sqlx::{PgPool, Postgres, QueryBuilder, Sqlite, SqlitePool};
+
+/// Where the decoded messages are stored.
+pub trait Storage: Send + Sync {
+    /// Stores a message together with its decoded values, in a single transaction,
+    /// returning the id of the message.
+    fn store_message<'a>(
+        &'a self,
+        message: &'a DataMessage,
+        instance_id: Option<&'a str>,
+    ) -> BoxFuture<'a, Result<i64, sqlx::Error>>;
+
+    /// Checks that the database can be reached.
+    fn ping(&self) -> BoxFuture<'_, Result<(), sqlx::Error>>;
+}
+
+pub struct PgStorage {
+    db_pool: PgPool,
+}
+
+impl PgStorage {
+    pub fn new(db_pool: PgPool) -> Self {
+        Self { db_pool }
+    }
+}
+
+impl Storage for PgStorage {
+    fn store_message<'a>(
+        &'a self,
+        message: &'a DataMessage,
+        instance_id: Option<&'a str>,
+    ) -> BoxFuture<'a, Result<i64, sqlx::Error>> {
+        async move {
+            let mut tx = self.db_pool.begin().await?;
+
+            let r = sqlx::query!("INSERT INTO inverter_messages (raw, type, header, time, instance_id, inverter_sn, type_byte) VALUES ($1, $2, $3, $4, $5, $6, $7) returning id",
+                message.raw, serde_json::to_string(&message.data_type).unwrap(), message.header, message.time, instance_id, message.inverter_serial(), message.type_byte())
+                .fetch_one(&mut *tx)
+                .await?;
+
+            if !message.data.is_empty() {
+                let mut query: QueryBuilder<Postgres> =
+                    QueryBuilder::new("INSERT INTO message_data (message_id, key, value) ");
+                query.push_values(&message.data, |mut row, (key, value)| {
+                    row.push_bind(r.id).push_bind(key).push_bind(value);
+                });
+                query.build().execute(&mut *tx).await?;
+            }
+
+            tx.commit().await?;
+
+            Ok(r.id.into())
+        }
+        .boxed()
+    }
+
+    fn ping(&self) -> BoxFuture<'_, Result<(), sqlx::Error>> {
+        async move {
+            sqlx::query("SELECT 1").execute(&self.db_pool).await?;
+            Ok(())
+        }
+        .boxed()
+    }
+}
+
+/// Stores the messages in a local SQLite file, with the same schema used for PostgreSQL.
+/// The queries are not checked at compile time, since the macros can only check those of
+/// the database in `DATABASE_URL`.
+pub struct SqliteStorage {
+    db_pool: SqlitePool,
+}
+
+impl SqliteStorage {
+    /// Opens the database file, creating it if needed, and brings its schema up-to-date.
+    pub async fn open(path: &str) -> Result<Self, String> {
+        let options = SqliteConnectOptions::new()
+            .filename(path)
+            .create_if_missing(true);
+
+        Self::open_with(options)
+            .await
+            .map_err(|e| format!("Failed to open the SQLite database \"{path}\".\n{e}"))
+    }
+
+    async fn open_with(options: SqliteConnectOptions) -> Result<Self, String> {
+        let db_pool = SqlitePool::connect_with(options.foreign_keys(true))
+            .await
+            .map_err(|e| e.to_string())?;
+
+        sqlx::migrate!("./migrations/sqlite")
+            .run(&db_pool)
+            .await
+            .map_err(|e| format!("Failed to run the SQLite database migrations.\n{e}"))?;
+
+        Ok(Self { db_pool })
+    }
+}
+
+impl Storage for SqliteStorage {
+    fn store_message<'a>(
+        &'a self,
+        message: &'a DataMessage,
+        instance_id: Option<&'a str>,
+    ) -> BoxFuture<'a, Result<i64, sqlx::Error>> {
+        async move {
+            let mut tx = self.db_pool.begin().await?;
+
+            let id: i64 = sqlx::query_scalar(
+                "INSERT INTO inverter_messages (raw, type, header, time, instance_id, inverter_sn, type_byte) VALUES ($1, $2, $3, $4, $5, $6, $7) returning id",
+            )
+            .bind(&message.raw)
+            .bind(serde_json::to_string(&message.data_type).unwrap())
+            .bind(&message.header)
+            .bind(message.time)
+            .bind(instance_id)
+            .bind(message.inverter_serial())
+            .bind(message.type_byte())
+            .fetch_one(&mut *tx)
+            .await?;
+
+            if !message.data.is_empty() {
+                let mut query: QueryBuilder<Sqlite> =
+                    QueryBuilder::new("INSERT INTO message_data (message_id, key, value) ");
+                query.push_values(&message.data, |mut row, (key, value)| {
+                    row.push_bind(id).push_bind(key).push_bind(value);
+                });
+                query.build().execute(&mut *tx).await?;
+            }
+
+            tx.commit().await?;
+
+            Ok(id)
+        }
+        .boxed()
+    }
+
+    fn ping(&self) -> BoxFuture<'_, Result<(), sqlx::Error>> {
+        async move {
+            sqlx::query("SELECT 1").execute(&self.db_pool).await?;
+            Ok(())
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use growatt_server::types::MessageType;
+    use std::str::FromStr;
+
+    async fn in_memory() -> SqliteStorage {
+        let options = SqliteConnectOptions::from_str("sqlite::memory:").unwrap();
+        SqliteStorage::open_with(options).await.unwrap()
+    }
+
+    fn message(values: &[(&str, &str)]) -> DataMessage {
+        let mut message = DataMessage::placeholder(
+            &[0x00, 0x01, 0x00, 0x06, 0x00, 0x02, 0x01, 0x04],
+            MessageType::Data4,
+        )
+        .unwrap();
+        for (key, value) in values {
+            message.data.insert(key.to_string(), value.to_string());
+        }
+        message
+    }
+
+    async fn count(storage: &SqliteStorage, table: &str) -> i64 {
+        sqlx::query_scalar(&format!("SELECT COUNT(*) FROM {table}"))
+            .fetch_one(&storage.db_pool)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn messages_are_stored_with_all_their_values() {
+        let storage = in_memory().await;
+        let values = [
+            ("PV1 Voltage", "230.5"),
+            ("PV2 Voltage", "229.1"),
+            ("Status", "Normal"),
+        ];
+
+        let id = storage
+            .store_message(&message(&values), Some("proxy-1"))
+            .await
+            .unwrap();
+
+        let mut stored: Vec<(String, String)> =
+            sqlx::query_as("SELECT key, value FROM message_data WHERE message_id = $1")
+                .bind(id)
+                .fetch_all(&storage.db_pool)
+                .await
+                .unwrap();
+        stored.sort();
+        let expected: Vec<_> = values
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        assert_eq!(stored, expected);
+        assert_eq!(count(&storage, "inverter_messages").await, 1);
+
+        let instance_id: Option<String> =
+            sqlx::query_scalar("SELECT instance_id FROM inverter_messages WHERE id = $1")
+                .bind(id)
+                .fetch_one(&storage.db_pool)
+                .await
+                .unwrap();
+        assert_eq!(instance_id.as_deref(), Some("proxy-1"));
+    }
+
+    #[tokio::test]
+    async fn a_failed_batch_leaves_nothing_behind() {
+        let storage = in_memory().await;
+        sqlx::query(
+            "CREATE TRIGGER reject_values BEFORE INSERT ON message_data WHEN NEW.key = 'rejected' \
+            BEGIN SELECT RAISE(ABORT, 'value rejected'); END",
+        )
+        .execute(&storage.db_pool)
+        .await
+        .unwrap();
+
+        let result = storage
+            .store_message(
+                &message(&[("PV1 Voltage", "230.5"), ("rejected", "1")]),
+                None,
+            )
+            .await;
+
+        assert!(result.is_err());
+        assert_eq!(count(&storage, "inverter_messages").await, 0);
+        assert_eq!(count(&storage, "message_data").await, 0);
+    }
+}
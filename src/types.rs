@@ -1,6 +1,6 @@
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, sqlx::Type, Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy, sqlx::Type, Serialize, Deserialize)]
 pub enum MessageType {
     Data3,
     Data4,
@@ -9,3 +9,17 @@ pub enum MessageType {
     Identify,
     Unknown,
 }
+
+impl From<u8> for MessageType {
+    /// Maps the type byte of the header (the 8th) to the message type.
+    fn from(value: u8) -> Self {
+        match value {
+            0x03 => MessageType::Data3,
+            0x04 => MessageType::Data4,
+            0x16 => MessageType::Ping,
+            0x18 => MessageType::Configure,
+            0x19 => MessageType::Identify,
+            _ => MessageType::Unknown,
+        }
+    }
+}
@@ -1,6 +1,16 @@
-pub fn unscramble_data(data: &[u8]) -> Vec<u8> {
+use crate::types::MessageType;
+use std::fmt;
+use std::future::Future;
+use std::time::Duration;
+
+/// Default key used by the dataloggers to scramble the messages.
+pub const DEFAULT_MASK: &[u8] = b"Growatt";
+
+/// Reverts the XOR scrambling of everything after the 8 bytes header.
+/// When `mask` is `None` (or empty), [`DEFAULT_MASK`] is used.
+pub fn unscramble_data(data: &[u8], mask: Option<&[u8]>) -> Vec<u8> {
     let ndecdata = data.len();
-    let mask = b"Growatt";
+    let mask = mask.filter(|m| !m.is_empty()).unwrap_or(DEFAULT_MASK);
 
     // Start the decrypt routine
     let mut unscrambled: Vec<u8> = data[..8].to_vec(); // Isolate the unscrambled header
@@ -13,26 +23,405 @@ pub fn unscramble_data(data: &[u8]) -> Vec<u8> {
     unscrambled
 }
 
+/// Scrambles a message before forwarding it, the inverse of [`unscramble_data`]
+/// (XOR-ing with the same mask twice gives back the original bytes).
+pub fn scramble_data(data: &[u8], mask: Option<&[u8]>) -> Vec<u8> {
+    unscramble_data(data, mask)
+}
+
+/// Guesses whether a message is scrambled, since some dataloggers (e.g. the older protocol
+/// versions) send them in clear. The header is never scrambled, so the guess is based on the
+/// payload: the messages of the dataloggers start with their serial number, 10 ASCII letters and
+/// digits.
+/// A message is considered in clear only when its serial number is readable as it is, but not
+/// once unscrambled: when both are readable, or the message is too short to tell, it is considered
+/// scrambled, as sent by most dataloggers.
+pub fn looks_scrambled(data: &[u8], mask: Option<&[u8]>) -> bool {
+    let Some(serial) = data.get(8..18) else {
+        return true;
+    };
+    let readable = |serial: &[u8]| serial.iter().all(u8::is_ascii_alphanumeric);
+
+    !readable(serial) || readable(&unscramble_data(&data[..18], mask)[8..])
+}
+
+/// Takes the next complete message out of the bytes received so far, using the length field of
+/// its header, or returns `None` when more bytes are needed.
+/// TCP doesn't preserve the boundaries of the messages: a single read may contain part of a
+/// message, or more than one.
+/// When the declared length is larger than `max_frame_size` the length can't be trusted, and the
+/// boundaries of the messages are lost: an error is returned, leaving `pending` as it is.
+pub fn next_frame(pending: &mut Vec<u8>, max_frame_size: usize) -> Result<Option<Vec<u8>>, String> {
+    let Some(length) = pending.get(4..6) else {
+        return Ok(None);
+    };
+
+    // Bytes up to the length field, the ones counted by it, then the CRC
+    let frame_size = 6 + u16::from_be_bytes([length[0], length[1]]) as usize + 2;
+    if frame_size > max_frame_size {
+        return Err(format!(
+            "Message declaring {frame_size} bytes, more than the maximum of {max_frame_size} bytes"
+        ));
+    }
+
+    if pending.len() < frame_size {
+        return Ok(None);
+    }
+
+    Ok(Some(pending.drain(..frame_size).collect()))
+}
+
+/// Builds the reply the upstream server would send to a message of a datalogger (as received),
+/// for when there is no upstream server. Without it the datalogger keeps sending the same data,
+/// and eventually reconnects.
+///
+/// The replies are the ones of the server of the Grott project
+/// (<https://github.com/johanmeijer/grott>, `grottserver.py`), which the dataloggers accept:
+/// - `Ping` (0x16): the message itself, echoed back.
+/// - `Data3` (0x03) and `Data4` (0x04): the same 8 bytes header, with the length (bytes 4..6) set
+///   to 3 as it only counts the unit id, the type and a single `0x00` byte of payload
+///   (`0x47` once scrambled with the default mask), followed by the CRC of all the bytes before.
+/// - `Identify` (0x19) and `Configure` (0x18): nothing. The dataloggers send these only as the
+///   answers to the register reads and writes of the server, which doesn't acknowledge them, and
+///   without a server no one sends those commands.
+///
+/// `scrambled` tells whether the message is scrambled, so that the reply is too.
+pub fn build_ack(
+    message_type: MessageType,
+    message: &[u8],
+    scrambled: bool,
+    mask: Option<&[u8]>,
+) -> Option<Vec<u8>> {
+    let header = message.get(..8)?;
+
+    match message_type {
+        MessageType::Ping => Some(message.to_vec()),
+        MessageType::Data3 | MessageType::Data4 => {
+            let mut ack = header.to_vec();
+            ack[4..6].copy_from_slice(&3u16.to_be_bytes());
+            ack.push(0x00);
+
+            if scrambled {
+                ack = scramble_data(&ack, mask);
+            }
+            let crc = crc16(&ack);
+            ack.extend_from_slice(&crc.to_be_bytes());
+            Some(ack)
+        }
+        _ => None,
+    }
+}
+
+/// Computes the Modbus CRC16 (polynomial 0xA001, initial value 0xFFFF).
+pub fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0xFFFF, |mut crc, byte| {
+        crc ^= u16::from(*byte);
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xA001
+            } else {
+                crc >> 1
+            };
+        }
+        crc
+    })
+}
+
+/// Checks the CRC16 closing every message (big-endian, computed on the scrambled bytes).
+pub fn verify_crc16(data: &[u8]) -> bool {
+    if data.len() < 3 {
+        return false;
+    }
+
+    let (payload, crc) = data.split_at(data.len() - 2);
+
+    crc16(payload) == u16::from_be_bytes([crc[0], crc[1]])
+}
+
+/// Runs `operation` up to `attempts` times, doubling the delay between each try starting from
+/// `base_delay`. Returns the last error if every attempt fails.
+pub async fn retry<T, E, F, Fut>(
+    attempts: u32,
+    base_delay: Duration,
+    mut operation: F,
+) -> Result<T, E>
+where
+    E: fmt::Display,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+{
+    let mut delay = base_delay;
+    let mut attempt = 1;
+
+    loop {
+        match operation().await {
+            Ok(value) => return Ok(value),
+            Err(e) if attempt < attempts => {
+                eprintln!("Attempt {attempt} of {attempts} failed, retrying in {delay:?}: {e}");
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
 pub fn hex_bytes_to_ascii(hex_bytes: &[u8]) -> String {
     hex_bytes.iter().map(|b| *b as char).collect()
 }
 
-#[allow(dead_code)]
-fn print_bytes(bytes: &[u8], n: usize) {
-    bytes.chunks(n).enumerate().for_each(|(i, chunk)| {
+/// Masks a serial number keeping only the first and last two characters, e.g. `AB******YZ`.
+pub fn mask_serial(serial: &str) -> String {
+    let len = serial.chars().count();
+
+    if len <= 4 {
+        return "*".repeat(len);
+    }
+
+    serial
+        .chars()
+        .enumerate()
+        .map(|(i, c)| if i < 2 || i >= len - 2 { c } else { '*' })
+        .collect()
+}
+
+/// Writes an offset-annotated hex and ASCII dump of `bytes`, `n` bytes per line:
+/// ```text
+/// 0000: 00 01 00 06 02 41 01 04  03 36 2b 47 ...   .....A...6+G...
+/// ```
+pub fn write_hex_dump(out: &mut impl fmt::Write, bytes: &[u8], n: usize) -> fmt::Result {
+    for (i, chunk) in bytes.chunks(n).enumerate() {
         if i != 0 {
-            println!();
+            writeln!(out)?;
         }
-        print!("{:04x}: ", i * n);
-        chunk.iter().enumerate().for_each(|(j, byte)| {
+        write!(out, "{:04x}: ", i * n)?;
+        for j in 0..n {
             if j != 0 && j % (n / 2) == 0 {
-                print!(" ");
+                write!(out, " ")?;
+            }
+            match chunk.get(j) {
+                Some(byte) => write!(out, "{:02x} ", byte)?,
+                None => write!(out, "   ")?,
             }
-            print!("{:02x} ", byte);
-        });
-        print!("  ");
-        chunk.iter().for_each(|byte| {
-            print!("{}", *byte as char);
-        });
-    });
+        }
+        write!(out, "  ")?;
+        for byte in chunk {
+            let c = *byte as char;
+            write!(out, "{}", if c.is_ascii_graphic() { c } else { '.' })?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A ping of the datalogger ABC1234567, as sent on the wire (scrambled)
+    const PING: &str =
+        "000100060020011606302c465347407244587761747447726f7761747447726f776174744772e95a";
+    /// A short Data4 message with the datalogger and inverter serials and a voltage, scrambled
+    const DATA4: &str = "000100060018010406302c465347407244583e2f224371475b4453457d461f56";
+
+    fn hex(text: &str) -> Vec<u8> {
+        (0..text.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&text[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn scrambled_messages_are_recognized() {
+        assert!(looks_scrambled(&hex(PING), None));
+        assert!(looks_scrambled(&hex(DATA4), None));
+    }
+
+    #[test]
+    fn plaintext_messages_are_recognized() {
+        assert!(!looks_scrambled(&unscramble_data(&hex(PING), None), None));
+        assert!(!looks_scrambled(&unscramble_data(&hex(DATA4), None), None));
+
+        let mask = Some(b"Custom".as_slice());
+        let plain = unscramble_data(&hex(DATA4), None);
+        assert!(looks_scrambled(&scramble_data(&plain, mask), mask));
+        assert!(!looks_scrambled(&plain, mask));
+    }
+
+    #[test]
+    fn serials_readable_both_ways_are_considered_scrambled() {
+        // 0050000005 scrambled is wBZGQDDwBZ, readable as well
+        let mut plain = unscramble_data(&hex(DATA4), None);
+        plain[8..18].copy_from_slice(b"0050000005");
+        let scrambled = scramble_data(&plain, None);
+        assert_eq!(&scrambled[8..18], b"wBZGQDDwBZ");
+
+        assert!(looks_scrambled(&scrambled, None));
+    }
+
+    #[test]
+    fn too_short_messages_are_considered_scrambled() {
+        assert!(looks_scrambled(&hex(PING)[..12], None));
+    }
+
+    #[test]
+    fn pings_are_echoed_back() {
+        let ack = build_ack(MessageType::Ping, &hex(PING), true, None).unwrap();
+
+        assert_eq!(ack, hex(PING));
+    }
+
+    #[test]
+    fn data_messages_are_acknowledged() {
+        let ack = build_ack(MessageType::Data4, &hex(DATA4), true, None).unwrap();
+
+        assert_eq!(ack, hex("0001000600030104472cd8"));
+        assert!(verify_crc16(&ack));
+        // The header of the message, with the length of a single byte of payload: 0x00
+        assert_eq!(unscramble_data(&ack[..9], None), hex("000100060003010400"));
+    }
+
+    #[test]
+    fn plaintext_data_messages_are_acknowledged_in_clear() {
+        let message = unscramble_data(&hex(DATA4), None);
+        let ack = build_ack(MessageType::Data4, &message, false, None).unwrap();
+
+        assert_eq!(ack, hex("0001000600030104001e98"));
+    }
+
+    #[test]
+    fn the_answers_to_the_commands_are_not_acknowledged() {
+        let mut identify = hex(DATA4);
+        identify[7] = 0x19;
+
+        assert_eq!(
+            build_ack(MessageType::Identify, &identify, true, None),
+            None
+        );
+        assert_eq!(
+            build_ack(MessageType::Configure, &identify, true, None),
+            None
+        );
+    }
+
+    #[test]
+    fn frames_split_across_reads_are_joined() {
+        let frame = hex(DATA4);
+
+        for split in 1..frame.len() {
+            let mut pending = frame[..split].to_vec();
+            assert_eq!(
+                next_frame(&mut pending, 65536),
+                Ok(None),
+                "split at {split}"
+            );
+
+            pending.extend_from_slice(&frame[split..]);
+            assert_eq!(next_frame(&mut pending, 65536), Ok(Some(frame.clone())));
+            assert!(pending.is_empty());
+        }
+    }
+
+    #[test]
+    fn concatenated_frames_are_split() {
+        let mut pending = hex(PING);
+        pending.extend_from_slice(&hex(DATA4));
+        // The beginning of a third one
+        pending.extend_from_slice(&hex(PING)[..10]);
+
+        assert_eq!(next_frame(&mut pending, 65536), Ok(Some(hex(PING))));
+        assert_eq!(next_frame(&mut pending, 65536), Ok(Some(hex(DATA4))));
+        assert_eq!(next_frame(&mut pending, 65536), Ok(None));
+        assert_eq!(pending, hex(PING)[..10]);
+    }
+
+    #[test]
+    fn implausible_lengths_are_an_error() {
+        let mut pending = hex(DATA4);
+        pending[4..6].copy_from_slice(&0xfff0u16.to_be_bytes());
+        let received = pending.clone();
+
+        assert_eq!(
+            next_frame(&mut pending, 1024),
+            Err("Message declaring 65528 bytes, more than the maximum of 1024 bytes".to_string())
+        );
+        assert_eq!(pending, received);
+    }
+
+    #[test]
+    fn scrambling_reverts_unscrambling() {
+        let frame = hex(DATA4);
+
+        let plain = unscramble_data(&frame, None);
+        assert_eq!(plain[..8], frame[..8], "the header is never scrambled");
+        assert_eq!(&plain[8..18], b"ABC1234567");
+        assert_eq!(scramble_data(&plain, None), frame);
+
+        let mask = Some(b"Custom".as_slice());
+        assert_eq!(scramble_data(&unscramble_data(&frame, mask), mask), frame);
+    }
+
+    #[test]
+    fn valid_crcs_are_accepted() {
+        assert!(verify_crc16(&hex(PING)));
+        assert!(verify_crc16(&hex(DATA4)));
+    }
+
+    #[test]
+    fn corrupted_messages_are_rejected() {
+        let mut payload = hex(DATA4);
+        payload[20] ^= 0x01;
+        assert!(!verify_crc16(&payload));
+
+        let mut crc = hex(PING);
+        *crc.last_mut().unwrap() ^= 0x80;
+        assert!(!verify_crc16(&crc));
+    }
+
+    #[tokio::test]
+    async fn transient_failures_are_retried() {
+        let mut attempts = 0;
+
+        let result = retry(3, Duration::from_millis(1), || {
+            attempts += 1;
+            let result = match attempts {
+                1 | 2 => Err("connection reset"),
+                _ => Ok(attempts),
+            };
+            async move { result }
+        })
+        .await;
+
+        assert_eq!(result, Ok(3));
+    }
+
+    #[tokio::test]
+    async fn the_last_error_is_returned() {
+        let mut attempts = 0;
+
+        let result: Result<(), String> = retry(2, Duration::from_millis(1), || {
+            attempts += 1;
+            let error = format!("attempt {attempts} failed");
+            async move { Err(error) }
+        })
+        .await;
+
+        assert_eq!(result, Err("attempt 2 failed".to_string()));
+    }
+
+    #[test]
+    fn too_short_messages_are_rejected() {
+        assert!(!verify_crc16(&[]));
+        assert!(!verify_crc16(&[0xff, 0xff]));
+    }
+
+    #[test]
+    fn serials_are_masked_but_the_first_and_last_two_characters() {
+        assert_eq!(mask_serial("ABC1234567"), "AB******67");
+        assert_eq!(mask_serial("ABCDE"), "AB*DE");
+        // Too short to show anything
+        assert_eq!(mask_serial("ABCD"), "****");
+        assert_eq!(mask_serial(""), "");
+    }
 }
@@ -0,0 +1,272 @@
+use growatt_server::data_message::{DataMessage, QUALITY_SUFFIX, RAW_QUALITY};
+use growatt_server::GrowattV6EnergyFragment;
+use sqlx::{PgPool, Postgres, QueryBuilder};
+use std::error::Error;
+
+/// Stores every Data4 message as a single row, with one column for each field of the mapping,
+/// next to the usual key/value rows of `message_data`.
+/// Numeric fields get a `DOUBLE PRECISION` column, everything else is stored as `TEXT`.
+/// The values that could not be scaled are left NULL, as they would look like valid readings;
+/// they are still in `message_data`, with their quality flag.
+pub struct WideTable {
+    db_pool: PgPool,
+    table: String,
+    columns: Vec<(String, bool)>,
+}
+
+impl WideTable {
+    pub fn new<'a>(
+        db_pool: PgPool,
+        table: &str,
+        mapping: impl IntoIterator<Item = &'a GrowattV6EnergyFragment>,
+    ) -> Result<Self, Box<dyn Error>> {
+        check_table_name(table)?;
+
+        Ok(Self {
+            db_pool,
+            table: table.to_string(),
+            columns: columns(mapping)?,
+        })
+    }
+
+    /// Creates the table, and adds the columns of the fields that were added to the mapping since
+    /// the last run. Columns of fields removed from the mapping are left untouched.
+    pub async fn prepare(&self) -> Result<(), sqlx::Error> {
+        sqlx::query(&format!(
+            "CREATE TABLE IF NOT EXISTS {} (\
+                message_id INTEGER PRIMARY KEY REFERENCES inverter_messages (id), \
+                time TIMESTAMPTZ NOT NULL, \
+                serial TEXT)",
+            self.table
+        ))
+        .execute(&self.db_pool)
+        .await?;
+
+        for (name, numeric) in &self.columns {
+            sqlx::query(&format!(
+                "ALTER TABLE {} ADD COLUMN IF NOT EXISTS {} {}",
+                self.table,
+                quote_identifier(name),
+                if *numeric { "DOUBLE PRECISION" } else { "TEXT" }
+            ))
+            .execute(&self.db_pool)
+            .await?;
+        }
+
+        Ok(())
+    }
+
+    pub async fn insert(&self, message_id: i64, message: &DataMessage) -> Result<(), sqlx::Error> {
+        let mut query: QueryBuilder<Postgres> = QueryBuilder::new(format!(
+            "INSERT INTO {} (message_id, time, serial",
+            self.table
+        ));
+        for (name, _) in &self.columns {
+            query.push(", ").push(quote_identifier(name));
+        }
+
+        query.push(") VALUES (");
+        let mut values = query.separated(", ");
+        values.push_bind(message_id as i32);
+        values.push_bind(message.time);
+        values.push_bind(message.inverter_serial());
+        for value in row(&self.columns, message) {
+            match value {
+                Value::Number(number) => values.push_bind(number),
+                Value::Text(text) => values.push_bind(text),
+            };
+        }
+        values.push_unseparated(")");
+
+        query.build().execute(&self.db_pool).await?;
+
+        Ok(())
+    }
+}
+
+/// The value of a field, with the type of its column.
+#[derive(Debug, PartialEq)]
+enum Value<'a> {
+    Number(Option<f64>),
+    Text(Option<&'a str>),
+}
+
+/// The values of the columns of the fields, in order.
+fn row<'a>(columns: &[(String, bool)], message: &'a DataMessage) -> Vec<Value<'a>> {
+    columns
+        .iter()
+        .map(|(name, numeric)| {
+            let raw = message.data.get(&format!("{name}{QUALITY_SUFFIX}"))
+                == Some(&RAW_QUALITY.to_string());
+            let value = message.data.get(name).filter(|_| !raw);
+
+            match numeric {
+                true => Value::Number(value.and_then(|v| v.parse::<f64>().ok())),
+                false => Value::Text(value.map(String::as_str)),
+            }
+        })
+        .collect()
+}
+
+/// Tables of the schema, which the wide table must not replace
+const RESERVED_TABLES: [&str; 3] = ["inverter_messages", "message_data", "_sqlx_migrations"];
+/// Columns of the wide table besides the ones of the fields
+const FIXED_COLUMNS: [&str; 3] = ["message_id", "time", "serial"];
+/// PostgreSQL truncates the identifiers longer than this (in bytes)
+const MAX_IDENTIFIER_LEN: usize = 63;
+
+fn check_table_name(table: &str) -> Result<(), String> {
+    let mut chars = table.chars();
+    let valid = chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !valid {
+        return Err(format!(
+            "Invalid wide table name \"{table}\": only letters, digits and underscores are allowed"
+        ));
+    }
+
+    // Unquoted names are case-insensitive
+    if RESERVED_TABLES.contains(&table.to_lowercase().as_str()) {
+        return Err(format!(
+            "Invalid wide table name \"{table}\": the table is already used to store the messages"
+        ));
+    }
+
+    Ok(())
+}
+
+/// The columns of the fields of the mapping, and whether they are numeric.
+/// Fails when a field would end up in the same column as another one, or as a fixed column.
+fn columns<'a>(
+    mapping: impl IntoIterator<Item = &'a GrowattV6EnergyFragment>,
+) -> Result<Vec<(String, bool)>, String> {
+    let mut columns: Vec<(String, bool)> = Vec::new();
+    let mut problems = Vec::new();
+
+    for fragment in mapping {
+        if columns.iter().any(|(name, _)| name == &fragment.name) {
+            continue;
+        }
+
+        let column = column_name(&fragment.name);
+        if FIXED_COLUMNS.contains(&column) {
+            problems.push(format!(
+                "{} has the same name as the {column} column of the wide table",
+                fragment.name
+            ));
+        } else if let Some((other, _)) =
+            columns.iter().find(|(name, _)| column_name(name) == column)
+        {
+            problems.push(format!(
+                "{} and {other} would be stored in the same column of the wide table, \
+                as the names are cut at {MAX_IDENTIFIER_LEN} bytes",
+                fragment.name
+            ));
+        }
+
+        columns.push((fragment.name.clone(), fragment.fragment_type.is_numeric()));
+    }
+
+    match problems.is_empty() {
+        true => Ok(columns),
+        false => Err(format!(
+            "Some fields of the mapping can't be stored in the wide table, rename them:\n{}",
+            problems.join("\n")
+        )),
+    }
+}
+
+/// The name of the column as stored by PostgreSQL.
+fn column_name(name: &str) -> &str {
+    let mut end = name.len().min(MAX_IDENTIFIER_LEN);
+    while !name.is_char_boundary(end) {
+        end -= 1;
+    }
+    &name[..end]
+}
+
+fn quote_identifier(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mapping(names: &[&str]) -> Vec<GrowattV6EnergyFragment> {
+        names
+            .iter()
+            .enumerate()
+            .map(|(i, name)| {
+                serde_json::from_value(serde_json::json!(
+                    {"name": name, "offset": i * 2, "length": 2, "type": "float", "fraction": 10}
+                ))
+                .unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn the_tables_of_the_messages_are_rejected() {
+        assert!(check_table_name("readings").is_ok());
+        assert!(check_table_name("message_data").is_err());
+        assert!(check_table_name("Inverter_Messages").is_err());
+        assert!(check_table_name("readings;drop").is_err());
+    }
+
+    #[test]
+    fn fields_named_as_the_fixed_columns_are_rejected() {
+        let error = columns(&mapping(&["PV1 Voltage", "time"])).unwrap_err();
+
+        assert!(error.contains("time has the same name as the time column"));
+    }
+
+    #[test]
+    fn fields_truncated_to_the_same_column_are_rejected() {
+        let long = "x".repeat(MAX_IDENTIFIER_LEN);
+        let error = columns(&mapping(&[&format!("{long}1"), &format!("{long}2")])).unwrap_err();
+
+        assert!(error.contains("would be stored in the same column"));
+    }
+
+    #[test]
+    fn values_stored_raw_are_left_null() {
+        let mut mapping = mapping(&["PV1 Voltage", "Energy"]);
+        mapping.push(
+            serde_json::from_str(
+                r#"{"name": "Status", "offset": 4, "length": 2, "type": "string"}"#,
+            )
+            .unwrap(),
+        );
+        // Energy can't be scaled with a zero fraction
+        mapping[1].fraction = Some(0);
+        let frame = [0, 1, 0, 6, 0, 8, 1, 4, 0x09, 0x01, 0x04, 0xd2, 0x4f, 0x4b];
+        let message = DataMessage::data4(&mapping, &frame).unwrap();
+        assert_eq!(message.data["Energy_quality"], RAW_QUALITY);
+
+        let columns = columns(&mapping).unwrap();
+        assert_eq!(
+            row(&columns, &message),
+            [
+                Value::Number(Some(230.5)),
+                Value::Number(None),
+                Value::Text(Some("OK")),
+            ]
+        );
+    }
+
+    #[test]
+    fn columns_follow_the_mapping() {
+        let columns = columns(&mapping(&["PV1 Voltage", "PV2 Voltage", "PV1 Voltage"])).unwrap();
+
+        assert_eq!(
+            columns,
+            [
+                ("PV1 Voltage".to_string(), true),
+                ("PV2 Voltage".to_string(), true)
+            ]
+        );
+    }
+}
@@ -0,0 +1,173 @@
+use axum::extract::ws::{Message, Utf8Bytes, WebSocket, WebSocketUpgrade};
+use axum::extract::{Query, State};
+use axum::response::Response;
+use chrono::{DateTime, Local};
+use growatt_server::mappings::Mappings;
+use growatt_server::types::MessageType;
+use growatt_server::DataMessage;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+use tokio_util::sync::CancellationToken;
+
+/// Messages kept for the clients that fall behind; the older ones are skipped for them.
+const CAPACITY: usize = 64;
+
+/// Sends the decoded messages to the WebSocket clients, as they are received.
+#[derive(Clone)]
+pub struct LiveFeed {
+    sender: broadcast::Sender<LiveMessage>,
+    /// Where the units of the values are found
+    mappings: Arc<Mappings>,
+    shutdown: CancellationToken,
+}
+
+#[derive(Clone)]
+struct LiveMessage {
+    inverter_serial: Option<String>,
+    json: Utf8Bytes,
+}
+
+#[derive(Serialize)]
+struct Payload<'a> {
+    #[serde(rename = "type")]
+    data_type: &'a MessageType,
+    time: DateTime<Local>,
+    logger_serial: Option<&'a str>,
+    inverter_serial: Option<&'a str>,
+    data: &'a HashMap<String, String>,
+    /// Units of the fields that have one in the mapping, as in `GET /inverters/{sn}/latest`
+    units: HashMap<&'a str, &'a str>,
+}
+
+#[derive(Deserialize)]
+pub struct Filter {
+    /// Sends only the messages of this inverter
+    serial: Option<String>,
+}
+
+impl LiveFeed {
+    pub fn new(mappings: Arc<Mappings>, shutdown: CancellationToken) -> Self {
+        let (sender, _) = broadcast::channel(CAPACITY);
+        Self {
+            sender,
+            mappings,
+            shutdown,
+        }
+    }
+
+    /// Never waits: a client that can't keep up misses the messages, instead of slowing down
+    /// the proxy.
+    pub fn publish(&self, message: &DataMessage) {
+        if self.sender.receiver_count() == 0 {
+            return;
+        }
+
+        let payload = Payload {
+            data_type: &message.data_type,
+            time: message.time,
+            logger_serial: message.logger_serial(),
+            inverter_serial: message.inverter_serial(),
+            data: &message.data,
+            units: self
+                .mappings
+                .fragments()
+                .filter(|f| message.data.contains_key(&f.name))
+                .filter_map(|f| Some((f.name.as_str(), f.unit.as_deref()?)))
+                .collect(),
+        };
+        let json = match serde_json::to_string(&payload) {
+            Ok(json) => json,
+            Err(e) => {
+                eprintln!("Could not serialize the message for the WebSocket clients: {e}");
+                return;
+            }
+        };
+
+        // Fails only when the last client disconnected in the meantime
+        let _ = self.sender.send(LiveMessage {
+            inverter_serial: message.inverter_serial().map(str::to_string),
+            json: json.into(),
+        });
+    }
+}
+
+/// `GET /ws`, optionally with `?serial=<inverter serial>`: streams the messages received from
+/// now on, one JSON object per WebSocket message, with the units of the values.
+pub async fn handler(
+    upgrade: WebSocketUpgrade,
+    Query(filter): Query<Filter>,
+    State(feed): State<LiveFeed>,
+) -> Response {
+    let messages = feed.sender.subscribe();
+    upgrade.on_upgrade(move |socket| forward(socket, messages, filter.serial, feed.shutdown))
+}
+
+async fn forward(
+    mut socket: WebSocket,
+    mut messages: broadcast::Receiver<LiveMessage>,
+    serial: Option<String>,
+    shutdown: CancellationToken,
+) {
+    loop {
+        let message = tokio::select! {
+            message = messages.recv() => message,
+            // Only to notice when the client goes away, what it sends is ignored
+            received = socket.recv() => match received {
+                Some(Ok(_)) => continue,
+                _ => return,
+            },
+            _ = shutdown.cancelled() => return,
+        };
+
+        match message {
+            Ok(message) => {
+                if serial.is_some() && message.inverter_serial != serial {
+                    continue;
+                }
+                if socket.send(Message::Text(message.json)).await.is_err() {
+                    return;
+                }
+            }
+            Err(RecvError::Lagged(skipped)) => {
+                eprintln!("A WebSocket client is too slow, {skipped} messages were not sent to it");
+            }
+            Err(RecvError::Closed) => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_messages_carry_the_units() {
+        let mapping: Vec<growatt_server::GrowattV6EnergyFragment> = serde_json::from_str(
+            r#"[
+                {"name": "PV1 Voltage", "offset": 0, "length": 2, "type": "float", "fraction": 10, "unit": "V"},
+                {"name": "Status", "offset": 2, "length": 1, "type": "int"}
+            ]"#,
+        )
+        .unwrap();
+        let mut frame = vec![
+            0x00, 0x01, 0x00, 0x06, 0x00, 0x05, 0x01, 0x04, 0x09, 0x01, 0x01,
+        ];
+        frame.extend_from_slice(&growatt_server::utils::crc16(&frame).to_be_bytes());
+        let message = DataMessage::data4(&mapping, &frame).unwrap();
+
+        let feed = LiveFeed::new(
+            Arc::new(Mappings::new(mapping, Vec::new(), None)),
+            CancellationToken::new(),
+        );
+        let mut receiver = feed.sender.subscribe();
+        feed.publish(&message);
+
+        let json: serde_json::Value =
+            serde_json::from_str(receiver.try_recv().unwrap().json.as_str()).unwrap();
+        assert_eq!(json["data"]["PV1 Voltage"], "230.5");
+        assert_eq!(json["units"], serde_json::json!({"PV1 Voltage": "V"}));
+    }
+}